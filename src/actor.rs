@@ -1,346 +1,2437 @@
-mod clients;
-mod common;
+pub mod clients;
+pub mod common;
+#[cfg(test)]
+mod tests;
 
-use crate::clients::dip20::Dip20;
-use crate::clients::sonic::Sonic;
-use crate::clients::xtc::{XTCBurnPayload, XTC};
+use crate::clients::sonic::SonicPairInfo;
+use crate::clients::xtc::XTCBurnPayload;
+use crate::common::arbitrage::{optimal_amount_in, Arbitrage};
+use crate::common::env::get_env;
+use crate::common::errors::{BotError, BotResult};
+use crate::common::grid::Grids;
+#[cfg(target_arch = "wasm32")]
 use crate::common::guards::controller_guard;
-use crate::common::types::{Currency, LimitOrder, MarketOrder, Order, OrderDirective, TargetPrice};
+use crate::common::history::TradeHistory;
+use crate::common::legacy::{LegacyLimitOrder, LegacyState};
+use crate::common::liquidity::{self, LpBook};
+use crate::common::oco::OcoGroups;
+use crate::common::paper::PaperAccount;
+use crate::common::quote;
+use crate::common::rebalance::{self, Rebalancer};
+use crate::common::registry::TokenRegistry;
+use crate::common::reservations::ReservationLedger;
+use crate::common::router::PoolGraph;
+use crate::common::strategy::{MarketSnapshot, StrategyAction, StrategyRegistry};
+use crate::common::types::{
+    ArbitrageConfig, ArbitrageLogPage, ArbitrageOpportunity, ArbitrageOutcome, BotConfig, Currency,
+    Grid, GridAction, GridConfig, GridId, GridStatus, Holding, LiquidityReceipt, LpEntry,
+    LpPosition, LpReport, MarketOrder, Mode, OcoGroupId, OcoGroupInfo, OcoStatus, Order,
+    OrderDirective, OrderInfo, OrderProgress, OrderRun, OrderStatus, RebalanceConfig,
+    RebalancePlan, Route, RunOutcome, ScheduledOrder, StrategyConfig, StrategyId, StrategyInstance,
+    SwapQuote, TargetPrice, TokenInfo, TokenMetadata, TradeFilter, TradeHistoryPage, TradeOrigin,
+    TradeRecord, TradingLimits,
+};
 use bigdecimal::num_bigint::{BigInt, ToBigInt};
 use bigdecimal::num_traits::Pow;
 use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
-use ic_cdk::api::{canister_balance, time};
-use ic_cdk::export::candid::{export_service, CandidType, Deserialize, Int, Nat, Principal};
-use ic_cdk::id;
+use ic_cdk::api::canister_balance;
+use ic_cdk::caller;
+use ic_cdk::export::candid::{
+    encode_one, export_service, CandidType, Deserialize, Int, Nat, Principal,
+};
 use ic_cdk::storage::{stable_restore, stable_save};
-use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade};
+// the methods are only exported into the canister, so the crate still links natively as a
+// library of the backtesting tool
+#[cfg(target_arch = "wasm32")]
+use ic_cdk_macros::{query, update};
 use ic_cron::implement_cron;
-use ic_cron::types::{Iterations, SchedulingInterval, TaskId};
+use ic_cron::task_scheduler::TaskScheduler;
+use ic_cron::types::{Iterations, ScheduledTask, SchedulingInterval, Task, TaskId};
+use std::collections::HashMap;
 
-#[update(guard = controller_guard)]
-pub async fn deposit(currency: Currency, amount: Nat) {
-    let state = *get_state();
-    let token = token_id_by_currency(currency);
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn deposit(currency: Currency, amount: Nat) -> BotResult<()> {
+    let state = get_state();
+    let token = token_id_by_currency(&currency)?;
 
-    Dip20::approve(&token, state.sonic_swap_canister, amount.clone())
+    get_env()
+        .token(token)
+        .approve(state.config.sonic_swap_canister, amount.clone())
         .await
-        .expect("Unable to approve tokens: call failed")
-        .0
-        .expect("Unable to approve tokens: internal error");
+        .map_err(BotError::call_rejected("approve"))?
+        .0?;
 
-    Sonic::deposit(&state.sonic_swap_canister, token, amount)
+    get_env()
+        .sonic()
+        .deposit(token, amount)
         .await
-        .expect("Unable to deposit tokens: call failed")
+        .map_err(BotError::call_rejected("deposit"))?
         .0
         .to_res()
-        .expect("Unable to deposit tokens: internal error");
+        .map_err(BotError::sonic)?;
+
+    Ok(())
 }
 
-#[update(guard = controller_guard)]
-pub async fn withdraw(currency: Currency, amount: Nat) {
-    let state = get_state();
-    let token = token_id_by_currency(currency);
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn withdraw(currency: Currency, amount: Nat) -> BotResult<()> {
+    ensure_live_mode("Withdrawing")?;
+    let token = token_id_by_currency(&currency)?;
 
-    Sonic::withdraw(&state.sonic_swap_canister, token, amount)
+    ensure_available_sonic_balance(currency, amount.clone()).await?;
+
+    get_env()
+        .sonic()
+        .withdraw(token, amount)
         .await
-        .expect("Unable to withdraw tokens: call failed")
+        .map_err(BotError::call_rejected("withdraw"))?
         .0
         .to_res()
-        .expect("Unable to withdraw tokens: internal error");
-}
+        .map_err(BotError::sonic)?;
 
-#[update(guard = controller_guard)]
-pub async fn mint_xtc_with_own_cycles(amount: u64) {
-    let state = get_state();
+    Ok(())
+}
 
-    XTC::mint(&state.xtc_canister, id(), amount)
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn mint_xtc_with_own_cycles(amount: u64) -> BotResult<()> {
+    get_env()
+        .xtc()
+        .mint(get_env().id(), amount)
         .await
-        .expect("Unable to mint XTC with cycles: call failed")
-        .0
-        .expect("Unable to mint XTC with cycles: internal error");
+        .map_err(BotError::call_rejected("mint"))?
+        .0?;
+
+    Ok(())
 }
 
-#[update(guard = controller_guard)]
-pub async fn burn_xtc_for_own_cycles(amount: u64) {
-    let state = get_state();
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn burn_xtc_for_own_cycles(amount: u64) -> BotResult<()> {
     let payload = XTCBurnPayload {
-        canister_id: id(),
+        canister_id: get_env().id(),
         amount,
     };
 
-    XTC::burn(&state.xtc_canister, payload)
+    get_env()
+        .xtc()
+        .burn(payload)
         .await
-        .expect("Unable to burn XTC for cycles: call failed")
-        .0
-        .expect("Unable to burn XTC for cycles: internal error");
+        .map_err(BotError::call_rejected("burn"))?
+        .0?;
+
+    Ok(())
 }
 
-#[update]
-pub async fn my_token_balance(currency: Currency) -> Nat {
-    let token = token_id_by_currency(currency);
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn my_token_balance(currency: Currency) -> BotResult<Nat> {
+    let token = token_id_by_currency(&currency)?;
 
-    let (balance,) = Dip20::balance_of(&token, id())
+    let (balance,) = get_env()
+        .token(token)
+        .balance_of(get_env().id())
         .await
-        .expect("Unable to fetch my balance at token");
+        .map_err(BotError::call_rejected("balanceOf"))?;
 
-    balance
+    Ok(balance)
 }
 
-#[update]
-pub async fn my_sonic_balance(currency: Currency) -> Nat {
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn my_sonic_balance(currency: Currency) -> BotResult<Nat> {
     let state = get_state();
-    let token = token_id_by_currency(currency);
+    let token = token_id_by_currency(&currency)?;
+
+    if state.mode == Mode::Paper {
+        return Ok(state.paper.balance(&currency));
+    }
 
-    let (balance,) = Sonic::balance_of(&state.sonic_swap_canister, token.to_text(), id())
+    let (balance,) = get_env()
+        .sonic()
+        .balance_of(token.to_text(), get_env().id())
         .await
-        .expect("Unable to fetch my balance at Sonic");
+        .map_err(BotError::call_rejected("balanceOf"))?;
+
+    Ok(balance)
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn my_reserved_balance(currency: Currency) -> Nat {
+    get_state().reservations.reserved(&currency)
+}
+
+async fn ensure_available_sonic_balance(currency: Currency, required: Nat) -> BotResult<()> {
+    let balance = my_sonic_balance(currency.clone()).await?;
+    let available = get_state().reservations.available(&currency, balance);
 
-    balance
+    if required > available {
+        return Err(BotError::InsufficientBalance {
+            currency,
+            required,
+            available,
+        });
+    }
+
+    Ok(())
 }
 
-#[query]
+#[cfg_attr(target_arch = "wasm32", query)]
 pub fn my_cycles_balance() -> u64 {
     canister_balance()
 }
 
-async fn get_swap_price_internal(give_currency: Currency, take_currency: Currency) -> BigDecimal {
-    let state = get_state();
-    let give_token = token_id_by_currency(give_currency);
-    let take_token = token_id_by_currency(take_currency);
+async fn get_reserves(give_currency: Currency, take_currency: Currency) -> BotResult<(Nat, Nat)> {
+    get_pair(&give_currency, &take_currency)
+        .await
+        .map(|(_, give_reserve, take_reserve)| (give_reserve, take_reserve))
+}
+
+/// The pool of the two tokens along with its reserves in the same order as the tokens
+async fn get_pair(
+    give_currency: &Currency,
+    take_currency: &Currency,
+) -> BotResult<(SonicPairInfo, Nat, Nat)> {
+    let give_token = token_id_by_currency(give_currency)?;
+    let take_token = token_id_by_currency(take_currency)?;
+
+    let (pair_opt,) = get_env()
+        .sonic()
+        .get_pair(give_token, take_token)
+        .await
+        .map_err(BotError::call_rejected("getPair"))?;
+
+    let pair_not_found = || BotError::PairNotFound {
+        give_currency: give_currency.clone(),
+        take_currency: take_currency.clone(),
+    };
+
+    let pair = pair_opt.ok_or_else(pair_not_found)?;
+    let (give_reserve, take_reserve) =
+        quote::oriented_reserves(&pair, give_token, take_token).ok_or_else(pair_not_found)?;
 
-    let (pair_opt,) = Sonic::get_pair(&state.sonic_swap_canister, give_token, take_token)
+    Ok((pair, give_reserve, take_reserve))
+}
+
+/// Adds `amount_a` of one token to the pool along with as much of the other one as the pool
+/// price requires. Neither amount may drop below its slippage bound.
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn add_liquidity(
+    currency_a: Currency,
+    currency_b: Currency,
+    amount_a: Nat,
+    max_slippage_bps: Option<u64>,
+) -> BotResult<LiquidityReceipt> {
+    ensure_live_mode("Adding liquidity")?;
+    let limits = liquidity_limits(max_slippage_bps)?;
+
+    let (pair, reserve_a, reserve_b) = get_pair(&currency_a, &currency_b).await?;
+    let amount_b =
+        liquidity::paired_amount(&amount_a, &reserve_a, &reserve_b).ok_or_else(|| {
+            BotError::InvalidArgument(String::from(
+                "The pool is empty, its price has to be set by the first deposit",
+            ))
+        })?;
+
+    ensure_available_sonic_balance(currency_a.clone(), amount_a.clone()).await?;
+    ensure_available_sonic_balance(currency_b.clone(), amount_b.clone()).await?;
+
+    let amount_a_min = amount_a.clone() * (quote::BPS - limits.max_slippage_bps) / quote::BPS;
+    let amount_b_min = amount_b.clone() * (quote::BPS - limits.max_slippage_bps) / quote::BPS;
+    let deadline = Int(BigInt::from(
+        get_env().time() + 1_000_000_000 * limits.deadline_secs,
+    ));
+
+    let token_a = token_id_by_currency(&currency_a)?;
+    let token_b = token_id_by_currency(&currency_b)?;
+    let lp_balance_before = my_lp_balance(&pair).await?;
+
+    let sonic_receipt = get_env()
+        .sonic()
+        .add_liquidity(
+            token_a,
+            token_b,
+            amount_a.clone(),
+            amount_b.clone(),
+            amount_a_min,
+            amount_b_min,
+            deadline,
+        )
         .await
-        .expect("Unable to fetch pair at Sonic");
+        .map_err(BotError::call_rejected("addLiquidity"))?
+        .0
+        .to_res()
+        .map_err(BotError::sonic)?;
+
+    // Sonic only returns the id of its transaction, so the minted LP tokens are measured by our
+    // balance change, falling back to what the pool should have minted
+    let lp_minted = match my_lp_balance(&pair).await {
+        Ok(lp_balance_after) if lp_balance_after > lp_balance_before => {
+            lp_balance_after - lp_balance_before
+        }
+        _ => (amount_a.clone() * pair.totalSupply.clone() / reserve_a.clone())
+            .min(amount_b.clone() * pair.totalSupply.clone() / reserve_b.clone()),
+    };
 
-    let pair = pair_opt.unwrap();
+    let (amount0, amount1) = if pair.token0 == token_a.to_text() {
+        (amount_a.clone(), amount_b.clone())
+    } else {
+        (amount_b.clone(), amount_a.clone())
+    };
+    let entry = LpEntry {
+        timestamp: get_env().time(),
+        reserve0: pair.reserve0.clone(),
+        reserve1: pair.reserve1.clone(),
+        total_supply: pair.totalSupply.clone(),
+        k_last: pair.kLast.clone(),
+        amount0,
+        amount1,
+        lp_minted,
+    };
 
-    let give_reserve = BigDecimal::from(pair.reserve0.0.to_bigint().unwrap());
-    let take_reserve = BigDecimal::from(pair.reserve1.0.to_bigint().unwrap());
+    let (token0, token1) = if pair.token0 == token_a.to_text() {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    };
+    get_state_mut()
+        .lp_book
+        .record_add(pair.id.clone(), token0, token1, entry);
 
-    give_reserve / take_reserve
+    Ok(LiquidityReceipt {
+        amount_a,
+        amount_b,
+        sonic_receipt,
+    })
 }
 
-#[update]
-pub async fn get_swap_price(give_currency: Currency, take_currency: Currency) -> f64 {
-    let give_token = token_id_by_currency(give_currency.clone());
-    let take_token = token_id_by_currency(take_currency.clone());
+/// Redeems LP tokens of the pool, getting back no less than the current share of its reserves
+/// minus the slippage tolerance
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn remove_liquidity(
+    currency_a: Currency,
+    currency_b: Currency,
+    lp_amount: Nat,
+    max_slippage_bps: Option<u64>,
+) -> BotResult<LiquidityReceipt> {
+    ensure_live_mode("Removing liquidity")?;
+    let limits = liquidity_limits(max_slippage_bps)?;
+
+    let (pair, reserve_a, reserve_b) = get_pair(&currency_a, &currency_b).await?;
+    let (amount_a, amount_b) =
+        liquidity::underlying_amounts(&lp_amount, &pair.totalSupply, &reserve_a, &reserve_b);
 
-    let price_bd = get_swap_price_internal(give_currency, take_currency).await;
+    let amount_a_min = amount_a.clone() * (quote::BPS - limits.max_slippage_bps) / quote::BPS;
+    let amount_b_min = amount_b.clone() * (quote::BPS - limits.max_slippage_bps) / quote::BPS;
+    let deadline = Int(BigInt::from(
+        get_env().time() + 1_000_000_000 * limits.deadline_secs,
+    ));
 
-    let (give_token_decimals,) = Dip20::decimals(&give_token)
+    let sonic_receipt = get_env()
+        .sonic()
+        .remove_liquidity(
+            token_id_by_currency(&currency_a)?,
+            token_id_by_currency(&currency_b)?,
+            lp_amount.clone(),
+            amount_a_min,
+            amount_b_min,
+            deadline,
+        )
         .await
-        .expect("Unable to fetch give_token decimals");
+        .map_err(BotError::call_rejected("removeLiquidity"))?
+        .0
+        .to_res()
+        .map_err(BotError::sonic)?;
+
+    get_state_mut().lp_book.record_remove(&pair.id, &lp_amount);
+
+    Ok(LiquidityReceipt {
+        amount_a,
+        amount_b,
+        sonic_receipt,
+    })
+}
+
+/// LP tokens of the pool the bot holds
+async fn my_lp_balance(pair: &SonicPairInfo) -> BotResult<Nat> {
+    let (lp_balances,) = get_env()
+        .sonic()
+        .get_user_LP_balances(get_env().id())
+        .await
+        .map_err(BotError::call_rejected("getUserLPBalances"))?;
+
+    Ok(lp_balances
+        .into_iter()
+        .find(|(lptoken, _)| *lptoken == pair.lptoken || *lptoken == pair.id)
+        .map(|(_, lp_balance)| lp_balance)
+        .unwrap_or_default())
+}
+
+/// Paper mode has no virtual LP tokens and paper balances can't back real withdrawals, so
+/// these only work for real
+fn ensure_live_mode(action: &str) -> BotResult<()> {
+    if get_state().mode == Mode::Paper {
+        return Err(BotError::InvalidArgument(format!(
+            "{} is not possible in paper mode",
+            action
+        )));
+    }
+
+    Ok(())
+}
+
+fn liquidity_limits(max_slippage_bps: Option<u64>) -> BotResult<TradingLimits> {
+    let mut limits = get_state().default_limits;
+    if let Some(max_slippage_bps) = max_slippage_bps {
+        limits.max_slippage_bps = max_slippage_bps;
+    }
+    limits.validate()?;
+
+    Ok(limits)
+}
 
-    let (take_token_decimals,) = Dip20::decimals(&take_token)
+/// Liquidity added by the bot against holding the same tokens. It's an update call, since the
+/// pools have to be read from Sonic.
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn get_lp_report() -> BotResult<Vec<LpReport>> {
+    let (pairs,) = get_env()
+        .sonic()
+        .get_all_pairs()
         .await
-        .expect("Unable to fetch take_token decimals");
+        .map_err(BotError::call_rejected("getAllPairs"))?;
+
+    let state = get_state();
+    let symbols = state.tokens.symbols_by_token_id();
 
-    let decimals_dif =
-        give_token_decimals.to_i32().unwrap() - take_token_decimals.to_i32().unwrap();
+    let mut reports: Vec<_> = state
+        .lp_book
+        .positions
+        .values()
+        .filter_map(|record| {
+            let pair = pairs.iter().find(|it| it.id == record.pair_id)?;
 
-    let decimals_modifier = 10f64.pow(decimals_dif);
+            Some(liquidity::report(record, pair, &symbols))
+        })
+        .collect();
+    reports.sort_by(|a, b| a.pair_id.cmp(&b.pair_id));
 
-    price_bd.to_f64().unwrap() * decimals_modifier
+    Ok(reports)
 }
 
-fn token_id_by_currency(currency: Currency) -> Principal {
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn list_lp_positions() -> BotResult<Vec<LpPosition>> {
     let state = get_state();
 
-    match currency {
-        Currency::XTC => state.xtc_canister,
-        Currency::WICP => state.wicp_canister,
+    let (lp_balances,) = get_env()
+        .sonic()
+        .get_user_LP_balances(get_env().id())
+        .await
+        .map_err(BotError::call_rejected("getUserLPBalances"))?;
+    let (pairs,) = get_env()
+        .sonic()
+        .get_all_pairs()
+        .await
+        .map_err(BotError::call_rejected("getAllPairs"))?;
+
+    let symbols = state.tokens.symbols_by_token_id();
+
+    let mut positions = Vec::new();
+
+    for (lptoken, lp_balance) in lp_balances {
+        if lp_balance.0 == 0u64.into() {
+            continue;
+        }
+
+        let pair = match pairs
+            .iter()
+            .find(|it| it.lptoken == lptoken || it.id == lptoken)
+        {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        let (token0, token1) = match (
+            Principal::from_text(&pair.token0),
+            Principal::from_text(&pair.token1),
+        ) {
+            (Ok(token0), Ok(token1)) => (token0, token1),
+            _ => continue,
+        };
+
+        let (amount0, amount1) = liquidity::underlying_amounts(
+            &lp_balance,
+            &pair.totalSupply,
+            &pair.reserve0,
+            &pair.reserve1,
+        );
+
+        positions.push(LpPosition {
+            pair_id: pair.id.clone(),
+            token0,
+            token1,
+            currency0: symbols.get(&pair.token0).cloned(),
+            currency1: symbols.get(&pair.token1).cloned(),
+            lp_balance,
+            total_supply: pair.totalSupply.clone(),
+            amount0,
+            amount1,
+        });
     }
+
+    Ok(positions)
 }
 
-#[update(guard = controller_guard)]
-pub async fn add_order(order: Order) -> Option<TaskId> {
-    match order {
-        Order::Market(market_order) => {
-            execute_market_order(market_order).await;
+async fn get_swap_price_internal(
+    give_currency: Currency,
+    take_currency: Currency,
+) -> BotResult<BigDecimal> {
+    let (give_reserve, take_reserve) = get_reserves(give_currency, take_currency).await?;
+
+    Ok(quote::spot_price(&give_reserve, &take_reserve))
+}
+
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn quote_swap(
+    give_currency: Currency,
+    take_currency: Currency,
+    directive: OrderDirective,
+) -> BotResult<SwapQuote> {
+    let route = find_route(&give_currency, &take_currency, &directive).await?;
+
+    Ok(route.quote)
+}
+
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn get_route(
+    give_currency: Currency,
+    take_currency: Currency,
+    directive: OrderDirective,
+) -> BotResult<Route> {
+    find_route(&give_currency, &take_currency, &directive).await
+}
+
+/// The best way to swap between two registered tokens through at most three Sonic pools
+async fn find_route(
+    give_currency: &Currency,
+    take_currency: &Currency,
+    directive: &OrderDirective,
+) -> BotResult<Route> {
+    token_id_by_currency(give_currency)?;
+    token_id_by_currency(take_currency)?;
+
+    let (pairs,) = get_env()
+        .sonic()
+        .get_all_pairs()
+        .await
+        .map_err(BotError::call_rejected("getAllPairs"))?;
+
+    PoolGraph::new(pairs, &get_state().tokens).best_route(give_currency, take_currency, directive)
+}
+
+#[cfg_attr(target_arch = "wasm32", update)]
+pub async fn get_swap_price(give_currency: Currency, take_currency: Currency) -> BotResult<f64> {
+    let decimals_modifier = get_decimals_modifier(&give_currency, &take_currency).await?;
+    let price_bd = get_swap_price_internal(give_currency, take_currency).await?;
+
+    Ok(price_bd.to_f64().unwrap() * decimals_modifier)
+}
+
+async fn get_decimals_modifier(
+    give_currency: &Currency,
+    take_currency: &Currency,
+) -> BotResult<f64> {
+    let give_token_decimals = token_metadata(give_currency).await?.decimals;
+    let take_token_decimals = token_metadata(take_currency).await?.decimals;
+
+    let decimals_dif = give_token_decimals as i32 - take_token_decimals as i32;
+
+    Ok(10f64.pow(decimals_dif))
+}
+
+fn token_id_by_currency(currency: &Currency) -> BotResult<Principal> {
+    get_state().tokens.canister_id(currency)
+}
+
+/// A swap path the way Sonic takes it
+fn token_path(path: &[Currency]) -> BotResult<Vec<String>> {
+    path.iter()
+        .map(|it| token_id_by_currency(it).map(|token| token.to_text()))
+        .collect()
+}
+
+/// Returns cached metadata of a registered token, fetching it first if there is none yet
+async fn token_metadata(currency: &Currency) -> BotResult<TokenMetadata> {
+    let token = get_state().tokens.get(currency)?;
+
+    match &token.metadata {
+        Some(metadata) => Ok(metadata.clone()),
+        None => fetch_token_metadata(currency).await,
+    }
+}
+
+async fn fetch_token_metadata(currency: &Currency) -> BotResult<TokenMetadata> {
+    let token = token_id_by_currency(currency)?;
+
+    let (metadata,) = get_env()
+        .token(token)
+        .get_metadata()
+        .await
+        .map_err(BotError::call_rejected("getMetadata"))?;
+
+    let metadata = TokenMetadata {
+        name: metadata.name,
+        decimals: metadata.decimals,
+        fee: metadata.fee,
+    };
+
+    get_state_mut()
+        .tokens
+        .set_metadata(currency, metadata.clone());
+
+    Ok(metadata)
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn register_token(canister_id: Principal) -> BotResult<TokenInfo> {
+    let (metadata,) = get_env()
+        .token(canister_id)
+        .get_metadata()
+        .await
+        .map_err(BotError::call_rejected("getMetadata"))?;
+
+    let info = get_state_mut().tokens.register(
+        metadata.symbol,
+        canister_id,
+        Some(TokenMetadata {
+            name: metadata.name,
+            decimals: metadata.decimals,
+            fee: metadata.fee,
+        }),
+    )?;
+
+    Ok(info.clone())
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn refresh_token_metadata(currency: Currency) -> BotResult<TokenMetadata> {
+    fetch_token_metadata(&currency).await
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn unregister_token(currency: Currency) -> BotResult<TokenInfo> {
+    let users = token_users(&currency);
+
+    if !users.is_empty() {
+        return Err(BotError::InvalidArgument(format!(
+            "Token {} is used by {}",
+            currency,
+            users.join(", ")
+        )));
+    }
+
+    get_state_mut().tokens.unregister(&currency)
+}
+
+/// Everything that would fail on its next tick if the token was gone
+fn token_users(currency: &Currency) -> Vec<String> {
+    let state = get_state();
+    let mut users = Vec::new();
+
+    let in_orders = list_orders().into_iter().any(|it| {
+        let market_order = it.order.market_order();
+
+        market_order.give_currency == *currency || market_order.take_currency == *currency
+    });
+    if in_orders {
+        users.push(String::from("pending orders"));
+    }
+
+    for grid in state.grids.list() {
+        let uses =
+            grid.config.base_currency == *currency || grid.config.quote_currency == *currency;
+
+        if uses && !matches!(grid.status, GridStatus::Stopped) {
+            users.push(format!("grid {}", grid.id));
+        }
+    }
 
-            None
+    for instance in state.strategies.list() {
+        if instance.kind.currencies().contains(currency) {
+            users.push(format!("strategy {}", instance.id));
         }
-        Order::Limit(limit_order) => {
-            // TODO: we need to somehow freeze tokens spent for limit orders
+    }
 
-            let task_id = cron_enqueue(
-                limit_order,
-                SchedulingInterval {
-                    delay_nano: 0,
-                    interval_nano: 1_000_000_000 * 10, // check each 10 seconds,
-                    iterations: Iterations::Exact(1),
-                },
-            )
-            .expect("Unable to schedule a task");
+    // a stopped rebalancer keeps its config for previews, only a scheduled one uses the token
+    let rebalancer = &state.rebalancer;
+    if let (Some(_), Some(config)) = (rebalancer.task_id, &rebalancer.config) {
+        let uses = config.quote_currency == *currency
+            || config.targets.iter().any(|it| it.currency == *currency);
 
-            Some(task_id)
+        if uses {
+            users.push(String::from("the rebalancer"));
         }
     }
+
+    let arbitrage = &state.arbitrage.config;
+    if arbitrage.enabled && arbitrage.base_currency == *currency {
+        users.push(String::from("arbitrage"));
+    }
+
+    users
 }
 
-#[heartbeat]
-pub fn tick() {
-    for task in cron_ready_tasks() {
-        // we only have limit orders here
-        let limit_order = task
-            .get_payload::<LimitOrder>()
-            .expect("Unable to parse limit order");
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn list_tokens() -> Vec<TokenInfo> {
+    get_state().tokens.list()
+}
 
-        ic_cdk::block_on(async {
-            let res = execute_limit_order(limit_order.clone()).await;
-
-            if !res {
-                cron_enqueue(
-                    limit_order,
-                    SchedulingInterval {
-                        delay_nano: 0,
-                        interval_nano: 1_000_000_000 * 10,
-                        iterations: Iterations::Exact(1),
-                    },
-                )
-                .expect("Unable to reschedule a task");
-            }
-        });
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn update_config(config: BotConfig) -> BotResult<()> {
+    let state = get_state();
+
+    if config.sonic_swap_canister != state.config.sonic_swap_canister && !list_orders().is_empty() {
+        return Err(BotError::InvalidArgument(String::from(
+            "Unable to switch Sonic canister while there are pending orders",
+        )));
     }
+
+    get_env()
+        .sonic_at(config.sonic_swap_canister)
+        .get_swap_info()
+        .await
+        .map_err(BotError::call_rejected("getSwapInfo"))?;
+
+    get_env()
+        .token(config.xtc_canister)
+        .decimals()
+        .await
+        .map_err(BotError::call_rejected("decimals"))?;
+
+    get_env()
+        .token(config.wicp_canister)
+        .decimals()
+        .await
+        .map_err(BotError::call_rejected("decimals"))?;
+
+    let state = get_state_mut();
+    state.tokens.set_well_known(&config);
+    state.config = config;
+
+    Ok(())
 }
 
-async fn execute_limit_order(order: LimitOrder) -> bool {
-    let price = get_swap_price(
-        order.market_order.give_currency.clone(),
-        order.market_order.take_currency.clone(),
-    )
-    .await;
-
-    match order.target_price_condition {
-        TargetPrice::MoreThan(target_price) => {
-            if price >= target_price {
-                execute_market_order(order.market_order).await;
-                true
-            } else {
-                false
-            }
-        }
-        TargetPrice::LessThan(target_price) => {
-            if price <= target_price {
-                execute_market_order(order.market_order).await;
-                true
-            } else {
-                false
-            }
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_config() -> BotConfig {
+    get_state().config
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn set_default_limits(limits: TradingLimits) -> BotResult<()> {
+    limits.validate()?;
+    get_state_mut().default_limits = limits;
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_default_limits() -> TradingLimits {
+    get_state().default_limits
+}
+
+/// Pending orders have reserved funds of the current mode, so they have to go first
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn set_mode(mode: Mode) -> BotResult<()> {
+    let state = get_state();
+    if mode == state.mode {
+        return Ok(());
+    }
+
+    // whatever is running holds positions bought with the funds of the current mode
+    let mut active = Vec::new();
+    if !list_orders().is_empty() {
+        active.push(String::from("pending orders"));
+    }
+    for grid in state.grids.list() {
+        if !matches!(grid.status, GridStatus::Stopped) {
+            active.push(format!("grid {}", grid.id));
         }
     }
+    for instance in state.strategies.list() {
+        active.push(format!("strategy {}", instance.id));
+    }
+    if state.rebalancer.task_id.is_some() {
+        active.push(String::from("the rebalancer"));
+    }
+    if state.arbitrage.config.enabled {
+        active.push(String::from("arbitrage"));
+    }
+
+    if !active.is_empty() {
+        return Err(BotError::InvalidArgument(format!(
+            "Unable to switch mode while these are active: {}",
+            active.join(", ")
+        )));
+    }
+
+    get_state_mut().mode = mode;
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_mode() -> Mode {
+    get_state().mode
 }
 
-async fn execute_market_order(order: MarketOrder) -> Nat {
-    let state = *get_state();
+/// Funds the virtual Sonic balance that paper trades are settled in
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn set_paper_balance(currency: Currency, amount: Nat) -> BotResult<()> {
+    token_id_by_currency(&currency)?;
 
-    let give_token = token_id_by_currency(order.give_currency.clone());
-    let take_token = token_id_by_currency(order.take_currency.clone());
+    get_state_mut().paper.set_balance(currency, amount);
 
-    let slippage_bd = BigDecimal::from_f64(0.99f64).unwrap(); // can tolerate 1% slippage
-    let deadline = Int(BigInt::from(time() + 1_000_000_000 * 20)); // 20 seconds til now
-    let this = id();
+    Ok(())
+}
 
-    let price_bd = get_swap_price_internal(order.give_currency, order.take_currency).await;
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_paper_balances() -> Vec<(Currency, Nat)> {
+    get_state().paper.list()
+}
 
-    match order.directive {
-        OrderDirective::GiveExact(give_amount) => {
-            let give_amount_bd = BigDecimal::from(give_amount.0.to_bigint().unwrap());
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn add_order(order: Order) -> BotResult<Option<TaskId>> {
+    match order {
+        Order::Market(market_order) => {
+            market_order
+                .limits(&get_state().default_limits)
+                .validate()?;
 
-            let take_amount_min_bd = give_amount_bd / price_bd * slippage_bd;
+            let give_amount = worst_case_give_amount(&market_order, None).await?;
+            ensure_available_sonic_balance(market_order.give_currency.clone(), give_amount).await?;
 
-            let take_amount_min = Nat(take_amount_min_bd
-                .to_bigint()
-                .unwrap()
-                .to_biguint()
-                .unwrap());
+            execute_market_order(market_order, TradeOrigin::Market).await?;
 
-            Sonic::swap_exact_tokens_for_tokens(
-                &state.sonic_swap_canister,
-                give_amount,
-                take_amount_min,
-                vec![give_token.to_text(), take_token.to_text()],
-                this,
-                deadline,
-            )
+            Ok(None)
+        }
+        Order::Limit(limit_order) => schedule_order(ScheduledOrder::Limit(limit_order))
             .await
-            .expect("Unable to swap exact tokens: call failed")
-            .0
-            .to_res()
-            .expect("Unable to swap exact tokens: internal error")
-        }
-        OrderDirective::TakeExact(take_amount) => {
-            let take_amount_bd = BigDecimal::from(take_amount.0.to_bigint().unwrap());
-
-            let give_amount_max_bd = take_amount_bd * price_bd * slippage_bd;
-            let give_amount_max = Nat(give_amount_max_bd
-                .to_bigint()
-                .unwrap()
-                .to_biguint()
-                .unwrap());
-
-            Sonic::swap_tokens_for_exact_tokens(
-                &state.sonic_swap_canister,
-                take_amount,
-                give_amount_max,
-                vec![give_token.to_text(), take_token.to_text()],
-                this,
-                deadline,
-            )
+            .map(Some),
+        Order::StopLoss(stop_loss) => schedule_order(ScheduledOrder::StopLoss(stop_loss))
             .await
-            .expect("Unable to swap to exact tokens: call failed")
-            .0
-            .to_res()
-            .expect("Unable to swap exact tokens: internal error")
+            .map(Some),
+        Order::TakeProfit(take_profit) => schedule_order(ScheduledOrder::TakeProfit(take_profit))
+            .await
+            .map(Some),
+        Order::TrailingStop(trailing_stop) => {
+            schedule_order(ScheduledOrder::TrailingStop(trailing_stop))
+                .await
+                .map(Some)
+        }
+        Order::Oco(legs) => {
+            if legs.len() < 2 {
+                return Err(BotError::InvalidArgument(String::from(
+                    "An OCO group needs at least two legs",
+                )));
+            }
+
+            if legs
+                .iter()
+                .any(|it| matches!(it, ScheduledOrder::Twap(_) | ScheduledOrder::Recurring(_)))
+            {
+                return Err(BotError::InvalidArgument(String::from(
+                    "Only orders triggered by price can be legs of an OCO group",
+                )));
+            }
+
+            let task_ids = schedule_orders(legs).await?;
+            let group_id = get_state_mut().oco_groups.create(task_ids);
+
+            Ok(Some(group_id))
+        }
+        Order::Twap(twap) => {
+            let runs_total = Some(twap.slices);
+            let task_id = schedule_order(ScheduledOrder::Twap(twap)).await?;
+
+            get_state_mut()
+                .progress
+                .insert(task_id, OrderProgress::new(runs_total));
+
+            Ok(Some(task_id))
+        }
+        Order::Recurring(recurring) => {
+            let runs_total = recurring.runs;
+            let task_id = schedule_order(ScheduledOrder::Recurring(recurring)).await?;
+
+            get_state_mut()
+                .progress
+                .insert(task_id, OrderProgress::new(runs_total));
+
+            Ok(Some(task_id))
         }
     }
 }
 
-// -------------------- STATE ---------------------
+async fn schedule_order(order: ScheduledOrder) -> BotResult<TaskId> {
+    let task_ids = schedule_orders(vec![order]).await?;
 
-#[derive(CandidType, Deserialize, Clone, Copy)]
-pub struct State {
-    pub xtc_canister: Principal,
-    pub wicp_canister: Principal,
-    pub sonic_swap_canister: Principal,
-    pub controller: Principal,
+    Ok(task_ids[0])
 }
 
-pub static mut STATE: Option<State> = None;
+/// Puts orders into the cron queue. If there is more than one, only one of them is ever going to
+/// fill, so together they only lock the largest amount any of them needs.
+async fn schedule_orders(orders: Vec<ScheduledOrder>) -> BotResult<Vec<TaskId>> {
+    let default_limits = get_state().default_limits;
+    let mut give_amounts = Vec::new();
 
-pub fn get_state() -> &'static State {
-    unsafe { STATE.as_ref().unwrap() }
-}
+    for order in &orders {
+        order.validate(&default_limits, get_env().time())?;
 
-#[init]
-pub fn init(controller: Principal) {
-    unsafe {
-        STATE = Some(State {
-            xtc_canister: Principal::from_text("aanaa-xaaaa-aaaah-aaeiq-cai").unwrap(),
-            wicp_canister: Principal::from_text("utozz-siaaa-aaaam-qaaxq-cai").unwrap(),
-            sonic_swap_canister: Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap(),
-            controller,
-        })
+        let give_amount =
+            worst_case_give_amount(order.market_order(), order.trigger_condition()).await?;
+        give_amounts.push(give_amount);
+    }
+
+    let mut required = HashMap::<Currency, Nat>::new();
+    for (order, give_amount) in orders.iter().zip(&give_amounts) {
+        let amount = required
+            .entry(order.market_order().give_currency.clone())
+            .or_default();
+
+        if give_amount > amount {
+            *amount = give_amount.clone();
+        }
+    }
+
+    for (currency, amount) in required {
+        ensure_available_sonic_balance(currency, amount).await?;
     }
+
+    let mut reserved = HashMap::<Currency, Nat>::new();
+    let mut task_ids = Vec::new();
+
+    for (order, give_amount) in orders.into_iter().zip(give_amounts) {
+        let give_currency = order.market_order().give_currency.clone();
+
+        let scheduling_interval = scheduling_interval(&order);
+        let task_id = match get_cron_state().enqueue(order, scheduling_interval, get_env().time()) {
+            Ok(task_id) => task_id,
+            Err(e) => {
+                // the orders only make sense together, so the ones already queued go away
+                for task_id in task_ids {
+                    cron_dequeue(task_id);
+                    get_state_mut().reservations.release(task_id);
+                }
+
+                return Err(BotError::scheduling(e));
+            }
+        };
+
+        // each order only reserves what the previous ones haven't reserved yet
+        let already_reserved = reserved.entry(give_currency.clone()).or_default();
+        let increment = if give_amount > *already_reserved {
+            let increment = give_amount.clone() - already_reserved.clone();
+            *already_reserved = give_amount;
+
+            increment
+        } else {
+            Nat::from(0)
+        };
+
+        get_state_mut()
+            .reservations
+            .reserve(task_id, give_currency, increment);
+
+        task_ids.push(task_id);
+    }
+
+    Ok(task_ids)
 }
 
-#[pre_upgrade]
-pub fn pre_upgrade_hook() {
-    unsafe {
-        stable_save((STATE.unwrap(), get_cron_state().clone()))
-            .expect("Unable to save the state to stable memory");
+fn scheduling_interval(order: &ScheduledOrder) -> SchedulingInterval {
+    match order {
+        ScheduledOrder::Twap(twap) => SchedulingInterval {
+            delay_nano: 0,
+            interval_nano: 1_000_000_000 * twap.interval_secs,
+            iterations: Iterations::Exact(twap.slices),
+        },
+        ScheduledOrder::Recurring(recurring) => SchedulingInterval {
+            delay_nano: 0,
+            interval_nano: 1_000_000_000 * recurring.interval_secs,
+            iterations: match recurring.runs {
+                Some(runs) => Iterations::Exact(runs),
+                None => Iterations::Infinite,
+            },
+        },
+        _ => SchedulingInterval {
+            delay_nano: 0,
+            interval_nano: 1_000_000_000 * 10, // check each 10 seconds,
+            iterations: Iterations::Infinite,
+        },
     }
 }
 
-#[post_upgrade]
-pub fn post_upgrade_hook() {
-    unsafe {
-        let (state, cron_state) =
-            stable_restore().expect("Unable to restore the state from stable memory");
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn list_orders() -> Vec<OrderInfo> {
+    let mut orders: Vec<_> = get_cron_state()
+        .get_tasks()
+        .into_iter()
+        .filter_map(order_info)
+        .collect();
+
+    orders.sort_by_key(|it| it.task_id);
+
+    orders
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_order(task_id: TaskId) -> Option<OrderInfo> {
+    get_cron_state()
+        .get_task_by_id(&task_id)
+        .and_then(order_info)
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_oco_group(group_id: OcoGroupId) -> Option<OcoGroupInfo> {
+    let group = get_state().oco_groups.get(group_id)?.clone();
+    let pending_legs = group.legs.iter().filter_map(|it| get_order(*it)).collect();
+
+    Some(OcoGroupInfo {
+        group,
+        pending_legs,
+    })
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_order_progress(task_id: TaskId) -> Option<OrderProgress> {
+    get_state().progress.get(&task_id).cloned()
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_trade_history(offset: u64, limit: u64, filter: Option<TradeFilter>) -> TradeHistoryPage {
+    get_state()
+        .history
+        .page(offset, limit, &filter.unwrap_or_default())
+}
+
+/// Cancelling a leg of an OCO group cancels the whole group
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn cancel_order(task_id: TaskId) -> BotResult<OrderInfo> {
+    let info = get_order(task_id).ok_or(BotError::OrderNotFound(task_id))?;
+
+    let state = get_state();
+    let executing = std::iter::once(task_id)
+        .chain(state.oco_groups.siblings(task_id))
+        .any(is_executing);
+    if executing {
+        return Err(BotError::InvalidArgument(format!(
+            "Order {} is being executed",
+            task_id
+        )));
+    }
 
-        STATE = Some(state);
-        set_cron_state(cron_state);
+    // a group without one of its legs makes no sense, so the whole group goes away
+    for sibling in get_state().oco_groups.siblings(task_id) {
+        close_order(sibling, OrderStatus::Cancelled);
     }
+    get_state_mut()
+        .oco_groups
+        .set_status(task_id, OcoStatus::Cancelled);
+
+    close_order(task_id, OrderStatus::Cancelled);
+
+    Ok(info)
+}
+
+/// Cancels every leg of the group that is still pending
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn cancel_oco_group(group_id: OcoGroupId) -> BotResult<OcoGroupInfo> {
+    let info = get_oco_group(group_id).ok_or(BotError::OcoGroupNotFound(group_id))?;
+    let leg = info
+        .pending_legs
+        .first()
+        .ok_or(BotError::OcoGroupNotFound(group_id))?;
+
+    cancel_order(leg.task_id)?;
+
+    Ok(info)
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_order_status(task_id: TaskId) -> Option<OrderStatus> {
+    let status = get_state().order_statuses.get(&task_id).cloned();
+
+    // an order being executed is still in the queue
+    if get_order(task_id).is_some() {
+        return Some(status.unwrap_or(OrderStatus::Pending));
+    }
+
+    status
+}
+
+// an order whose swap keeps failing for reasons that could go away is given up after this many
+// attempts
+const MAX_EXECUTION_ATTEMPTS: u64 = 5;
+
+fn is_executing(task_id: TaskId) -> bool {
+    matches!(
+        get_state().order_statuses.get(&task_id),
+        Some(OrderStatus::Executing)
+    )
+}
+
+/// Marks an order as executing right before its swap, so it can't be executed twice by
+/// overlapping heartbeats or cancelled mid-swap. The order stays in the queue with its funds
+/// reserved until the swap is done, so it can be tried again if the swap fails. While one leg of
+/// an OCO group is executing, the other legs wait.
+fn claim_order(task_id: TaskId) -> bool {
+    let state = get_state();
+    let executing = std::iter::once(task_id)
+        .chain(state.oco_groups.siblings(task_id))
+        .any(is_executing);
+
+    if executing || get_cron_state().get_task_by_id(&task_id).is_none() {
+        return false;
+    }
+
+    get_state_mut()
+        .order_statuses
+        .insert(task_id, OrderStatus::Executing);
+
+    true
+}
+
+/// Puts an order whose swap has failed back to waiting for its next check, returns false if it
+/// has run out of attempts
+fn release_order(task_id: TaskId) -> bool {
+    let state = get_state_mut();
+    let attempts = state.failed_attempts.entry(task_id).or_default();
+    *attempts += 1;
+
+    if *attempts >= MAX_EXECUTION_ATTEMPTS {
+        return false;
+    }
+
+    state.order_statuses.remove(&task_id);
+
+    true
+}
+
+/// Takes an order out of the cron queue and drops everything the canister keeps for it, returns
+/// false if the order has already left the queue
+fn close_order(task_id: TaskId, status: OrderStatus) -> bool {
+    if cron_dequeue(task_id).is_none() {
+        return false;
+    }
+
+    finish_order(task_id, status);
+
+    true
+}
+
+/// Drops everything the canister keeps for an order that has left the cron queue
+fn finish_order(task_id: TaskId, status: OrderStatus) {
+    let state = get_state_mut();
+
+    state.reservations.release(task_id);
+    state.trailing_peaks.remove(&task_id);
+    state.check_counts.remove(&task_id);
+    state.failed_attempts.remove(&task_id);
+    state.order_statuses.insert(task_id, status);
+}
+
+/// The most an order may spend: the exact amount for GiveExact, or the quoted amount at the worst
+/// price the order could fill at (plus its slippage tolerance) for TakeExact
+async fn worst_case_give_amount(
+    order: &MarketOrder,
+    condition: Option<TargetPrice>,
+) -> BotResult<Nat> {
+    if let OrderDirective::GiveExact(give_amount) = &order.directive {
+        return Ok(give_amount.clone());
+    }
+
+    let route = find_route(&order.give_currency, &order.take_currency, &order.directive).await?;
+
+    let mut give_amount_bd = quote::to_decimal(&route.quote.give_amount);
+
+    if let Some(condition) = condition {
+        let spot_price_bd = quote::path_spot_price(&route.reserves);
+        let decimals_modifier =
+            get_decimals_modifier(&order.give_currency, &order.take_currency).await?;
+
+        let invalid_price = || BotError::InvalidArgument(String::from("Invalid target price"));
+
+        let worst_price_bd = match condition {
+            // the order only fills while the price is below the target
+            TargetPrice::LessThan(target_price) => {
+                BigDecimal::from_f64(target_price / decimals_modifier).ok_or_else(invalid_price)?
+            }
+            // nothing bounds the price from above, so the best we can do is the current one
+            TargetPrice::MoreThan(target_price) => {
+                let target_price_bd = BigDecimal::from_f64(target_price / decimals_modifier)
+                    .ok_or_else(invalid_price)?;
+
+                if target_price_bd > spot_price_bd {
+                    target_price_bd
+                } else {
+                    spot_price_bd.clone()
+                }
+            }
+        };
+
+        // fee and price impact scale together with the price
+        give_amount_bd = give_amount_bd * worst_price_bd / spot_price_bd;
+    }
+
+    let give_amount = give_amount_bd
+        .to_bigint()
+        .and_then(|it| it.to_biguint())
+        .map(Nat)
+        .ok_or_else(|| BotError::InvalidArgument(String::from("Invalid target price")))?;
+
+    let limits = order.limits(&get_state().default_limits);
+
+    Ok(give_amount * (quote::BPS + limits.max_slippage_bps) / quote::BPS)
+}
+
+fn order_info(task: ScheduledTask) -> Option<OrderInfo> {
+    // a task the bot is unable to parse is never executed
+    let order = task.get_payload::<ScheduledOrder>().ok()?;
+
+    // the same formula ic-cron uses when it puts the task back into the queue
+    let next_check_at = match task.rescheduled_at {
+        Some(rescheduled_at) => rescheduled_at + task.scheduling_interval.interval_nano,
+        None => task.scheduled_at + task.scheduling_interval.delay_nano,
+    };
+
+    Some(OrderInfo {
+        task_id: task.id,
+        order,
+        created_at: task.scheduled_at,
+        next_check_at,
+        trailing_peak: get_state().trailing_peaks.get(&task.id).cloned(),
+        checks_made: get_state()
+            .check_counts
+            .get(&task.id)
+            .cloned()
+            .unwrap_or_default(),
+        failed_attempts: get_state()
+            .failed_attempts
+            .get(&task.id)
+            .cloned()
+            .unwrap_or_default(),
+        oco_group_id: get_state().oco_groups.group_by_leg.get(&task.id).cloned(),
+    })
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn start_grid(config: GridConfig) -> BotResult<GridId> {
+    config.validate()?;
+
+    get_decimals_modifier(&config.quote_currency, &config.base_currency).await?;
+    get_reserves(config.quote_currency.clone(), config.base_currency.clone()).await?;
+
+    Ok(get_state_mut().grids.create(config, get_env().time()))
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn pause_grid(grid_id: GridId) -> BotResult<()> {
+    set_grid_status(grid_id, GridStatus::Paused)
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn resume_grid(grid_id: GridId) -> BotResult<()> {
+    set_grid_status(grid_id, GridStatus::Running)
+}
+
+/// Positions the grid holds are kept as they are and no longer reserved, it's up to the
+/// controller to sell them
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn stop_grid(grid_id: GridId) -> BotResult<Grid> {
+    set_grid_status(grid_id, GridStatus::Stopped)?;
+    get_state_mut().reservations.release_grid(grid_id);
+
+    Ok(get_state().grids.get(grid_id).cloned().unwrap())
+}
+
+fn set_grid_status(grid_id: GridId, status: GridStatus) -> BotResult<()> {
+    let grid = get_state_mut()
+        .grids
+        .get_mut(grid_id)
+        .ok_or(BotError::GridNotFound(grid_id))?;
+
+    if let GridStatus::Stopped = grid.status {
+        return Err(BotError::InvalidArgument(String::from(
+            "The grid is stopped",
+        )));
+    }
+
+    grid.status = status;
+    // crossings that happened while the grid was paused are not traded
+    grid.last_price = None;
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_grid(grid_id: GridId) -> Option<Grid> {
+    get_state().grids.get(grid_id).cloned()
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn list_grids() -> Vec<Grid> {
+    get_state().grids.list()
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn set_arbitrage_config(config: ArbitrageConfig) -> BotResult<()> {
+    config.validate()?;
+    token_id_by_currency(&config.base_currency)?;
+
+    get_state_mut().arbitrage.config = config;
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_arbitrage_config() -> ArbitrageConfig {
+    get_state().arbitrage.config.clone()
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_arbitrage_log(offset: u64, limit: u64) -> ArbitrageLogPage {
+    get_state().arbitrage.page(offset, limit)
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn add_strategy(config: StrategyConfig, interval_secs: u64) -> BotResult<StrategyId> {
+    config.validate(&get_state().default_limits)?;
+
+    if interval_secs == 0 {
+        return Err(BotError::InvalidArgument(String::from(
+            "Strategy interval should be positive",
+        )));
+    }
+
+    for currency in config.currencies() {
+        token_metadata(&currency).await?;
+    }
+
+    Ok(get_state_mut()
+        .strategies
+        .add(config.build(), interval_secs, get_env().time()))
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn pause_strategy(strategy_id: StrategyId) -> BotResult<()> {
+    set_strategy_enabled(strategy_id, false)
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn resume_strategy(strategy_id: StrategyId) -> BotResult<()> {
+    set_strategy_enabled(strategy_id, true)
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn remove_strategy(strategy_id: StrategyId) -> BotResult<StrategyInstance> {
+    get_state_mut()
+        .strategies
+        .remove(strategy_id)
+        .ok_or(BotError::StrategyNotFound(strategy_id))
+}
+
+fn set_strategy_enabled(strategy_id: StrategyId, enabled: bool) -> BotResult<()> {
+    let instance = get_state_mut()
+        .strategies
+        .get_mut(strategy_id)
+        .ok_or(BotError::StrategyNotFound(strategy_id))?;
+
+    instance.enabled = enabled;
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_strategy(strategy_id: StrategyId) -> Option<StrategyInstance> {
+    get_state().strategies.get(strategy_id).cloned()
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn list_strategies() -> Vec<StrategyInstance> {
+    get_state().strategies.list()
+}
+
+/// Replaces the rebalancing config and restarts its cron task, the first check happens right away
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn start_rebalancing(config: RebalanceConfig) -> BotResult<()> {
+    config.validate()?;
+    token_id_by_currency(&config.quote_currency)?;
+    for target in &config.targets {
+        token_id_by_currency(&target.currency)?;
+    }
+
+    let task_id = get_cron_state()
+        .enqueue(
+            (),
+            SchedulingInterval {
+                delay_nano: 0,
+                interval_nano: 1_000_000_000 * config.interval_secs,
+                iterations: Iterations::Infinite,
+            },
+            get_env().time(),
+        )
+        .map_err(BotError::scheduling)?;
+
+    let rebalancer = &mut get_state_mut().rebalancer;
+    if let Some(task_id) = rebalancer.task_id.replace(task_id) {
+        cron_dequeue(task_id);
+    }
+    rebalancer.config = Some(config);
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub fn stop_rebalancing() -> BotResult<()> {
+    let task_id = get_state_mut()
+        .rebalancer
+        .task_id
+        .take()
+        .ok_or_else(|| BotError::InvalidArgument(String::from("Rebalancing is not running")))?;
+
+    cron_dequeue(task_id);
+
+    Ok(())
+}
+
+#[cfg_attr(target_arch = "wasm32", query)]
+pub fn get_rebalance_config() -> Option<RebalanceConfig> {
+    get_state().rebalancer.config.clone()
+}
+
+/// The trades rebalancing would make right now, with the given config or the current one
+#[cfg_attr(target_arch = "wasm32", update(guard = controller_guard))]
+pub async fn dry_run_rebalance(config: Option<RebalanceConfig>) -> BotResult<RebalancePlan> {
+    let config = match config.or_else(|| get_state().rebalancer.config.clone()) {
+        Some(config) => config,
+        None => {
+            return Err(BotError::InvalidArgument(String::from(
+                "Rebalancing is not configured",
+            )))
+        }
+    };
+    config.validate()?;
+
+    plan_rebalance(&config).await
+}
+
+async fn plan_rebalance(config: &RebalanceConfig) -> BotResult<RebalancePlan> {
+    let mut holdings = Vec::new();
+
+    for target in &config.targets {
+        let sonic_balance = my_sonic_balance(target.currency.clone()).await?;
+        // paper trades only ever touch the virtual Sonic balances
+        let wallet_balance = match get_state().mode {
+            Mode::Paper => Nat::from(0),
+            Mode::Live => my_token_balance(target.currency.clone()).await?,
+        };
+        let balance = sonic_balance.clone() + wallet_balance.clone();
+
+        let value = if target.currency == config.quote_currency || balance.0 == 0u64.into() {
+            balance
+        } else {
+            find_route(
+                &target.currency,
+                &config.quote_currency,
+                &OrderDirective::GiveExact(balance),
+            )
+            .await?
+            .quote
+            .take_amount
+        };
+
+        holdings.push(Holding {
+            currency: target.currency.clone(),
+            sonic_balance,
+            wallet_balance,
+            value,
+            weight_bps: 0,
+            target_weight_bps: target.weight_bps,
+        });
+    }
+
+    Ok(rebalance::plan(config, holdings))
+}
+
+async fn rebalance() -> BotResult<()> {
+    let config = match get_state().rebalancer.config.clone() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    get_state_mut().rebalancer.last_run_at = Some(get_env().time());
+
+    let plan = plan_rebalance(&config).await?;
+
+    for trade in plan.trades {
+        let available = my_sonic_balance(trade.give_currency.clone()).await?;
+        let available = get_state()
+            .reservations
+            .available(&trade.give_currency, available);
+
+        // buys are paid for with what the sells brought, which may be a bit less than quoted
+        let trade = match trade.directive {
+            OrderDirective::GiveExact(amount) if amount > available => MarketOrder {
+                directive: OrderDirective::GiveExact(available),
+                ..trade
+            },
+            _ => trade,
+        };
+
+        if let OrderDirective::GiveExact(amount) = &trade.directive {
+            if amount.0 == 0u64.into() {
+                continue;
+            }
+        }
+
+        if let Err(e) = execute_market_order(trade.clone(), TradeOrigin::Rebalance).await {
+            get_env().print(&format!(
+                "Unable to rebalance {} into {}: {:?}",
+                trade.give_currency, trade.take_currency, e
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[heartbeat]
+pub fn tick() {
+    for task in get_cron_state().iterate(get_env().time()) {
+        if get_state().rebalancer.task_id == Some(task.id) {
+            ic_cdk::block_on(async {
+                if let Err(e) = rebalance().await {
+                    get_env().print(&format!("Unable to rebalance: {:?}", e));
+                }
+            });
+            continue;
+        }
+
+        let order = match task.get_payload::<ScheduledOrder>() {
+            Ok(order) => order,
+            Err(e) => {
+                get_env().print(&format!(
+                    "Unable to parse scheduled order {}: {}",
+                    task.id, e
+                ));
+                continue;
+            }
+        };
+
+        ic_cdk::block_on(async move {
+            if let Err(e) = execute_scheduled_order(task.id, order).await {
+                get_env().print(&format!("Unable to execute order {}: {:?}", task.id, e));
+            }
+        });
+    }
+
+    // grids are checked as often as orders are
+    for grid_id in get_state_mut()
+        .grids
+        .take_due(get_env().time(), 1_000_000_000 * 10)
+    {
+        ic_cdk::block_on(async move {
+            if let Err(e) = check_grid(grid_id).await {
+                get_env().print(&format!("Unable to check grid {}: {:?}", grid_id, e));
+            }
+        });
+    }
+
+    if get_state_mut().arbitrage.take_due(get_env().time()) {
+        ic_cdk::block_on(async {
+            if let Err(e) = scan_arbitrage().await {
+                get_env().print(&format!("Unable to scan for arbitrage: {:?}", e));
+            }
+        });
+    }
+
+    let strategy_ids = get_state_mut().strategies.take_due(get_env().time());
+    if !strategy_ids.is_empty() {
+        ic_cdk::block_on(async move {
+            if let Err(e) = run_strategies(strategy_ids).await {
+                get_env().print(&format!("Unable to run strategies: {:?}", e));
+            }
+        });
+    }
+}
+
+/// All strategies due this heartbeat share one snapshot and take turns acting on it
+async fn run_strategies(strategy_ids: Vec<StrategyId>) -> BotResult<()> {
+    let snapshot = market_snapshot().await?;
+
+    for strategy_id in strategy_ids {
+        let actions = match get_state_mut().strategies.get_mut(strategy_id) {
+            Some(instance) => instance.kind.strategy_mut().on_tick(&snapshot),
+            None => continue,
+        };
+
+        for action in actions {
+            let result = execute_strategy_action(strategy_id, &action).await;
+            if let Err(e) = &result {
+                get_env().print(&format!(
+                    "Strategy {} was unable to act: {:?}",
+                    strategy_id, e
+                ));
+            }
+
+            // the strategy could have been removed while its action was in flight
+            if let Some(instance) = get_state_mut().strategies.get_mut(strategy_id) {
+                instance
+                    .kind
+                    .strategy_mut()
+                    .on_action_result(&action, result.as_ref().map_err(|e| format!("{:?}", e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn execute_strategy_action(
+    strategy_id: StrategyId,
+    action: &StrategyAction,
+) -> BotResult<TradeRecord> {
+    match action {
+        StrategyAction::Swap(order) => {
+            let give_amount = worst_case_give_amount(order, None).await?;
+            ensure_available_sonic_balance(order.give_currency.clone(), give_amount).await?;
+
+            execute_market_order(order.clone(), TradeOrigin::Strategy(strategy_id)).await
+        }
+    }
+}
+
+async fn market_snapshot() -> BotResult<MarketSnapshot> {
+    let state = get_state();
+
+    let (pairs,) = get_env()
+        .sonic()
+        .get_all_pairs()
+        .await
+        .map_err(BotError::call_rejected("getAllPairs"))?;
+
+    let sonic_balances = match state.mode {
+        Mode::Paper => state.paper.balances.clone(),
+        Mode::Live => {
+            let (user_info,) = get_env()
+                .sonic()
+                .get_user_info(get_env().id())
+                .await
+                .map_err(BotError::call_rejected("getUserInfo"))?;
+            let symbols = state.tokens.symbols_by_token_id();
+
+            user_info
+                .balances
+                .into_iter()
+                .filter_map(|(token, balance)| {
+                    Some((symbols.get(&token.to_text())?.clone(), balance))
+                })
+                .collect()
+        }
+    };
+    let mut balances = HashMap::new();
+    let mut decimals = HashMap::new();
+
+    for token in state.tokens.list() {
+        let balance = sonic_balances
+            .get(&token.symbol)
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0));
+        balances.insert(
+            token.symbol.clone(),
+            state.reservations.available(&token.symbol, balance),
+        );
+
+        // strategies fetch metadata of their tokens when they are added
+        if let Some(metadata) = token.metadata {
+            decimals.insert(token.symbol, metadata.decimals);
+        }
+    }
+
+    Ok(MarketSnapshot {
+        timestamp: get_env().time(),
+        pools: PoolGraph::new(pairs, &state.tokens),
+        balances,
+        decimals,
+    })
+}
+
+/// Logs every profitable triangle through the base currency and takes the best one, if it earns
+/// enough
+async fn scan_arbitrage() -> BotResult<()> {
+    let config = get_state().arbitrage.config.clone();
+
+    let (pairs,) = get_env()
+        .sonic()
+        .get_all_pairs()
+        .await
+        .map_err(BotError::call_rejected("getAllPairs"))?;
+    let graph = PoolGraph::new(pairs, &get_state().tokens);
+
+    let balance = my_sonic_balance(config.base_currency.clone()).await?;
+    let available = get_state()
+        .reservations
+        .available(&config.base_currency, balance);
+    let max_amount_in = available.min(config.max_trade_size.clone());
+
+    let mut best: Option<ArbitrageOpportunity> = None;
+
+    for path in graph.triangles(&config.base_currency) {
+        let reserves = graph.path_reserves(&path).unwrap();
+
+        let amount_in = match optimal_amount_in(&reserves) {
+            Some(amount_in) => amount_in.min(max_amount_in.clone()),
+            None => continue,
+        };
+        let quote =
+            match quote::quote_path(&OrderDirective::GiveExact(amount_in.clone()), &reserves) {
+                Some(quote) if quote.take_amount > amount_in => quote,
+                _ => continue,
+            };
+
+        let profit = quote.take_amount.clone() - amount_in.clone();
+        let profit_bps = (profit * quote::BPS / amount_in.clone())
+            .0
+            .to_u64()
+            .unwrap_or(u64::MAX);
+
+        let arbitrage = &mut get_state_mut().arbitrage;
+        let opportunity = ArbitrageOpportunity {
+            id: arbitrage.next_id(),
+            found_at: get_env().time(),
+            path,
+            amount_in,
+            quoted_amount_out: quote.take_amount,
+            profit_bps,
+            outcome: ArbitrageOutcome::Found,
+        };
+
+        get_env().print(&format!(
+            "Arbitrage opportunity {} found: {:?} earns {} bps",
+            opportunity.id, opportunity.path, opportunity.profit_bps
+        ));
+        arbitrage.log(opportunity.clone());
+
+        let is_better = match &best {
+            Some(best) => {
+                opportunity.quoted_amount_out.clone() - opportunity.amount_in.clone()
+                    > best.quoted_amount_out.clone() - best.amount_in.clone()
+            }
+            None => true,
+        };
+        if opportunity.profit_bps >= config.min_profit_bps && is_better {
+            best = Some(opportunity);
+        }
+    }
+
+    // the cycles are likely to share pools, so only one of them is taken per scan
+    if let Some(mut opportunity) = best {
+        let min_amount_out =
+            opportunity.amount_in.clone() * (quote::BPS + config.min_profit_bps) / quote::BPS;
+
+        opportunity.outcome = match execute_arbitrage(&opportunity, min_amount_out).await {
+            Ok(record) => ArbitrageOutcome::Taken {
+                trade_id: record.id,
+                profit: Int::from(record.take_amount) - Int::from(record.give_amount),
+            },
+            Err(e) => ArbitrageOutcome::Failed(format!("{:?}", e)),
+        };
+
+        get_env().print(&format!(
+            "Arbitrage opportunity {} taken: {:?}",
+            opportunity.id, opportunity.outcome
+        ));
+        get_state_mut().arbitrage.update(opportunity);
+    }
+
+    Ok(())
+}
+
+/// Swaps along the whole cycle at once, so Sonic reverts it if it doesn't earn `min_amount_out`
+async fn execute_arbitrage(
+    opportunity: &ArbitrageOpportunity,
+    min_amount_out: Nat,
+) -> BotResult<TradeRecord> {
+    let state = get_state();
+    let currency = opportunity.path[0].clone();
+    let path = token_path(&opportunity.path)?;
+    let deadline = Int(BigInt::from(
+        get_env().time() + 1_000_000_000 * state.default_limits.deadline_secs,
+    ));
+
+    let (sonic_receipt, take_amount) = if state.mode == Mode::Paper {
+        let take_amount = opportunity.quoted_amount_out.clone();
+        get_state_mut().paper.settle_swap(
+            &currency,
+            &opportunity.amount_in,
+            &currency,
+            &take_amount,
+        )?;
+
+        (Nat::from(0), take_amount)
+    } else {
+        let balance_before = my_sonic_balance(currency.clone()).await?;
+
+        let sonic_receipt = get_env()
+            .sonic()
+            .swap_exact_tokens_for_tokens(
+                opportunity.amount_in.clone(),
+                min_amount_out,
+                path,
+                get_env().id(),
+                deadline,
+            )
+            .await
+            .map_err(BotError::call_rejected("swapExactTokensForTokens"))?
+            .0
+            .to_res()
+            .map_err(BotError::sonic)?;
+
+        // the swap gives and takes the same token, so the balance only changes by the profit
+        let take_amount = match my_sonic_balance(currency.clone()).await {
+            Ok(balance_after)
+                if balance_after.clone() + opportunity.amount_in.clone() > balance_before =>
+            {
+                balance_after + opportunity.amount_in.clone() - balance_before
+            }
+            _ => opportunity.quoted_amount_out.clone(),
+        };
+
+        (sonic_receipt, take_amount)
+    };
+
+    let quoted_price = quote::price(&opportunity.amount_in, &opportunity.quoted_amount_out);
+    let realised_price = quote::price(&opportunity.amount_in, &take_amount);
+
+    let history = &mut get_state_mut().history;
+    let record = TradeRecord {
+        id: history.next_id(),
+        origin: TradeOrigin::Arbitrage(opportunity.id),
+        give_currency: currency.clone(),
+        take_currency: currency,
+        give_amount: opportunity.amount_in.clone(),
+        take_amount,
+        path: opportunity.path.clone(),
+        quoted_price: quoted_price.to_f64().unwrap(),
+        realised_price: realised_price.to_f64().unwrap(),
+        timestamp: get_env().time(),
+        sonic_receipt,
+        mode: state.mode,
+    };
+    history.push(record.clone());
+
+    Ok(record)
+}
+
+async fn check_grid(grid_id: GridId) -> BotResult<()> {
+    let config = get_state()
+        .grids
+        .get(grid_id)
+        .ok_or(BotError::GridNotFound(grid_id))?
+        .config
+        .clone();
+
+    let decimals_modifier =
+        get_decimals_modifier(&config.quote_currency, &config.base_currency).await?;
+    let price =
+        get_swap_price_internal(config.quote_currency.clone(), config.base_currency.clone())
+            .await?
+            .to_f64()
+            .unwrap()
+            * decimals_modifier;
+
+    for action in get_state_mut().grids.take_actions(grid_id, price) {
+        if let Err(e) = execute_grid_action(grid_id, &config, action).await {
+            get_env().print(&format!(
+                "Unable to execute {:?} of grid {}: {:?}",
+                action, grid_id, e
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn execute_grid_action(
+    grid_id: GridId,
+    config: &GridConfig,
+    action: GridAction,
+) -> BotResult<()> {
+    match action {
+        GridAction::Buy(level) => {
+            if !get_state_mut().grids.start_buy(grid_id, level) {
+                return Ok(());
+            }
+
+            let result = buy_grid_level(grid_id, level, config).await;
+
+            let state = get_state_mut();
+            state.reservations.release_grid_level(grid_id, level);
+
+            match result {
+                Ok(record) => {
+                    // the bought tokens stay locked until the level above sells them
+                    let stopped = matches!(
+                        state.grids.get(grid_id),
+                        Some(grid) if matches!(grid.status, GridStatus::Stopped)
+                    );
+                    if !stopped {
+                        state.reservations.reserve_grid_level(
+                            grid_id,
+                            level,
+                            config.base_currency.clone(),
+                            record.take_amount.clone(),
+                        );
+                    }
+
+                    state.grids.record_buy(
+                        grid_id,
+                        level,
+                        record.take_amount,
+                        record.give_amount,
+                        record.timestamp,
+                    );
+                }
+                Err(e) => {
+                    state.grids.cancel_buy(grid_id, level);
+
+                    return Err(e);
+                }
+            }
+        }
+        GridAction::Sell(level) => {
+            let state = get_state_mut();
+            let position = match state.grids.take_position(grid_id, level) {
+                Some(position) => position,
+                None => return Ok(()),
+            };
+            state.reservations.release_grid_level(grid_id, level);
+
+            let order = MarketOrder {
+                give_currency: config.base_currency.clone(),
+                take_currency: config.quote_currency.clone(),
+                directive: OrderDirective::GiveExact(position.base_amount.clone()),
+                max_slippage_bps: None,
+                deadline_secs: None,
+                max_price_impact_bps: None,
+            };
+
+            let result = match ensure_available_sonic_balance(
+                order.give_currency.clone(),
+                position.base_amount.clone(),
+            )
+            .await
+            {
+                Ok(()) => execute_market_order(order, TradeOrigin::Grid(grid_id)).await,
+                Err(e) => Err(e),
+            };
+
+            let state = get_state_mut();
+            match result {
+                Ok(record) => state
+                    .grids
+                    .record_sell(grid_id, position, record.take_amount),
+                Err(e) => {
+                    state.reservations.reserve_grid_level(
+                        grid_id,
+                        level,
+                        config.base_currency.clone(),
+                        position.base_amount.clone(),
+                    );
+                    state.grids.restore_position(grid_id, level, position);
+
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Buys a level with the quote funds nothing else has reserved, and keeps them reserved while the
+/// swap is in flight
+async fn buy_grid_level(
+    grid_id: GridId,
+    level: u64,
+    config: &GridConfig,
+) -> BotResult<TradeRecord> {
+    let order = MarketOrder {
+        give_currency: config.quote_currency.clone(),
+        take_currency: config.base_currency.clone(),
+        directive: OrderDirective::TakeExact(config.level_size.clone()),
+        max_slippage_bps: None,
+        deadline_secs: None,
+        max_price_impact_bps: None,
+    };
+
+    let give_amount = worst_case_give_amount(&order, None).await?;
+    ensure_available_sonic_balance(order.give_currency.clone(), give_amount.clone()).await?;
+
+    get_state_mut().reservations.reserve_grid_level(
+        grid_id,
+        level,
+        order.give_currency.clone(),
+        give_amount,
+    );
+
+    execute_market_order(order, TradeOrigin::Grid(grid_id)).await
+}
+
+async fn execute_scheduled_order(task_id: TaskId, order: ScheduledOrder) -> BotResult<()> {
+    let market_order = order.market_order().clone();
+
+    let triggered = match &order {
+        ScheduledOrder::Twap(_) | ScheduledOrder::Recurring(_) => {
+            return execute_run(task_id, &order).await
+        }
+        // the swap started by an earlier check is still in flight
+        _ if is_executing(task_id) => return Ok(()),
+        ScheduledOrder::Limit(limit_order) => {
+            let checks_made = get_state()
+                .check_counts
+                .get(&task_id)
+                .cloned()
+                .unwrap_or_default();
+
+            if limit_order
+                .time_in_force()
+                .is_expired(get_env().time(), checks_made)
+            {
+                close_order(task_id, OrderStatus::Expired);
+
+                return Ok(());
+            }
+
+            get_state_mut()
+                .check_counts
+                .insert(task_id, checks_made + 1);
+
+            // the price of the route the order is going to be swapped through, which doesn't
+            // have to be a direct pool
+            let price = get_quoted_price(&market_order).await?;
+
+            order.is_triggered(price, &mut None)
+        }
+        // protective orders look at the price the whole position would actually be sold at
+        ScheduledOrder::StopLoss(_) | ScheduledOrder::TakeProfit(_) => {
+            let price = get_quoted_price(&market_order).await?;
+
+            order.is_triggered(price, &mut None)
+        }
+        ScheduledOrder::TrailingStop(_) => {
+            let price = get_quoted_price(&market_order).await?;
+
+            // the order could have been cancelled or filled while the price was being quoted
+            if get_cron_state().get_task_by_id(&task_id).is_none() {
+                return Ok(());
+            }
+
+            let peaks = &mut get_state_mut().trailing_peaks;
+            let mut peak = peaks.get(&task_id).cloned();
+            let triggered = order.is_triggered(price, &mut peak);
+            peaks.insert(task_id, peak.unwrap());
+
+            triggered
+        }
+    };
+
+    if !triggered {
+        return Ok(());
+    }
+
+    if !claim_order(task_id) {
+        return Ok(());
+    }
+
+    let result = execute_market_order(market_order, order.trade_origin(task_id)).await;
+
+    let pending_siblings: Vec<_> = get_state()
+        .oco_groups
+        .siblings(task_id)
+        .into_iter()
+        .filter(|it| get_order(*it).is_some())
+        .collect();
+
+    match &result {
+        Ok(_) => {
+            for sibling in pending_siblings {
+                close_order(sibling, OrderStatus::Cancelled);
+            }
+            close_order(task_id, OrderStatus::Filled);
+
+            get_state_mut()
+                .oco_groups
+                .set_status(task_id, OcoStatus::Filled(task_id));
+        }
+        // the order is checked again, and swapped once more if it's still triggered then
+        Err(e) if e.is_retryable() && release_order(task_id) => {}
+        Err(_) => {
+            // the other legs still protect the position, so they keep waiting and take over the
+            // funds this one had reserved
+            let state = get_state_mut();
+            for sibling in &pending_siblings {
+                if state.reservations.transfer(task_id, *sibling) {
+                    break;
+                }
+            }
+            close_order(task_id, OrderStatus::Failed);
+
+            if pending_siblings.is_empty() {
+                get_state_mut()
+                    .oco_groups
+                    .set_status(task_id, OcoStatus::Failed(task_id));
+            }
+        }
+    }
+
+    result.map(|_| ())
+}
+
+/// Executes the next run of a TWAP or a recurring order. Their tasks stay in the queue for as
+/// many heartbeats as there are runs, ic-cron removes them by itself after the last one.
+async fn execute_run(task_id: TaskId, order: &ScheduledOrder) -> BotResult<()> {
+    let index = match get_state_mut().progress.get_mut(&task_id) {
+        Some(progress) => {
+            progress.runs_started += 1;
+            progress.runs_started - 1
+        }
+        None => return Ok(()),
+    };
+
+    let (market_order, price_limit) = match order {
+        ScheduledOrder::Twap(twap) => (twap.slice(index), twap.price_limit.clone()),
+        ScheduledOrder::Recurring(recurring) => {
+            if recurring.has_ended(get_env().time()) {
+                let status = get_state().progress[&task_id].final_status();
+
+                cron_dequeue(task_id);
+                finish_order(task_id, status);
+
+                return Ok(());
+            }
+
+            (
+                recurring.market_order.clone(),
+                recurring.max_price.map(TargetPrice::LessThan),
+            )
+        }
+        _ => unreachable!("Only TWAP and recurring orders are executed in runs"),
+    };
+
+    let outcome = match get_quoted_price(&market_order).await {
+        Ok(price) if matches!(&price_limit, Some(limit) if !limit.is_met(price)) => {
+            RunOutcome::Skipped {
+                quoted_price: price,
+            }
+        }
+        Ok(_) => match execute_market_order(market_order, order.trade_origin(task_id)).await {
+            Ok(record) => RunOutcome::Filled {
+                trade_id: record.id,
+                give_amount: record.give_amount,
+                take_amount: record.take_amount,
+                realised_price: record.realised_price,
+            },
+            Err(e) => RunOutcome::Failed(format!("{:?}", e)),
+        },
+        Err(e) => RunOutcome::Failed(format!("{:?}", e)),
+    };
+
+    let state = get_state_mut();
+    let progress = state.progress.get_mut(&task_id).unwrap();
+    progress.record(OrderRun {
+        index,
+        executed_at: get_env().time(),
+        outcome,
+    });
+
+    // the order could have been cancelled or ended while the run was in progress
+    if !state.reservations.by_order.contains_key(&task_id) {
+        return Ok(());
+    }
+
+    if progress.is_finished() {
+        let status = progress.final_status();
+        finish_order(task_id, status);
+    } else if let ScheduledOrder::Twap(twap) = order {
+        // a TWAP order only keeps reserved what its unfinished slices need
+        let finished_amount = progress
+            .runs
+            .iter()
+            .fold(Nat::from(0), |sum, it| sum + twap.slice_amount(it.index));
+        let remaining_amount = twap.total_amount() - finished_amount;
+
+        let (currency, _) = state.reservations.release(task_id).unwrap();
+        state
+            .reservations
+            .reserve(task_id, currency, remaining_amount);
+    }
+
+    Ok(())
+}
+
+/// Price of the order if it was executed right now, the fee and price impact included
+async fn get_quoted_price(order: &MarketOrder) -> BotResult<f64> {
+    let decimals_modifier =
+        get_decimals_modifier(&order.give_currency, &order.take_currency).await?;
+    let route = find_route(&order.give_currency, &order.take_currency, &order.directive).await?;
+    let price = quote::price(&route.quote.give_amount, &route.quote.take_amount);
+
+    Ok(price.to_f64().unwrap() * decimals_modifier)
+}
+
+async fn execute_market_order(order: MarketOrder, origin: TradeOrigin) -> BotResult<TradeRecord> {
+    let state = get_state();
+    let limits = order.limits(&state.default_limits);
+    limits.validate()?;
+
+    let deadline = Int(BigInt::from(
+        get_env().time() + 1_000_000_000 * limits.deadline_secs,
+    ));
+    let this = get_env().id();
+
+    let decimals_modifier =
+        get_decimals_modifier(&order.give_currency, &order.take_currency).await?;
+    let route = find_route(&order.give_currency, &order.take_currency, &order.directive).await?;
+    let quote = route.quote;
+    let path = token_path(&route.path)?;
+
+    if quote.price_impact_bps > limits.max_price_impact_bps {
+        return Err(BotError::PriceImpactTooHigh {
+            price_impact_bps: quote.price_impact_bps,
+            max_price_impact_bps: limits.max_price_impact_bps,
+        });
+    }
+
+    // Sonic only returns the id of its transaction, so the side of the swap that is not fixed
+    // by the order is measured by our balance change
+    let (sonic_receipt, give_amount, take_amount) = match (state.mode, order.directive.clone()) {
+        // nobody trades between the quote and a paper swap, so it fills exactly at the quote
+        (Mode::Paper, _) => {
+            get_state_mut().paper.settle_swap(
+                &order.give_currency,
+                &quote.give_amount,
+                &order.take_currency,
+                &quote.take_amount,
+            )?;
+
+            (
+                Nat::from(0),
+                quote.give_amount.clone(),
+                quote.take_amount.clone(),
+            )
+        }
+        (Mode::Live, OrderDirective::GiveExact(give_amount)) => {
+            let take_amount_min =
+                quote.take_amount.clone() * (quote::BPS - limits.max_slippage_bps) / quote::BPS;
+
+            let balance_before = my_sonic_balance(order.take_currency.clone()).await?;
+
+            let receipt = get_env()
+                .sonic()
+                .swap_exact_tokens_for_tokens(
+                    give_amount.clone(),
+                    take_amount_min,
+                    path.clone(),
+                    this,
+                    deadline,
+                )
+                .await
+                .map_err(BotError::call_rejected("swapExactTokensForTokens"))?
+                .0
+                .to_res()
+                .map_err(BotError::sonic)?;
+
+            let take_amount = match my_sonic_balance(order.take_currency.clone()).await {
+                Ok(balance_after) if balance_after > balance_before => {
+                    balance_after - balance_before
+                }
+                _ => quote.take_amount.clone(),
+            };
+
+            (receipt, give_amount, take_amount)
+        }
+        (Mode::Live, OrderDirective::TakeExact(take_amount)) => {
+            let give_amount_max =
+                quote.give_amount.clone() * (quote::BPS + limits.max_slippage_bps) / quote::BPS;
+
+            let balance_before = my_sonic_balance(order.give_currency.clone()).await?;
+
+            let receipt = get_env()
+                .sonic()
+                .swap_tokens_for_exact_tokens(
+                    take_amount.clone(),
+                    give_amount_max,
+                    path.clone(),
+                    this,
+                    deadline,
+                )
+                .await
+                .map_err(BotError::call_rejected("swapTokensForExactTokens"))?
+                .0
+                .to_res()
+                .map_err(BotError::sonic)?;
+
+            let give_amount = match my_sonic_balance(order.give_currency.clone()).await {
+                Ok(balance_after) if balance_before > balance_after => {
+                    balance_before - balance_after
+                }
+                _ => quote.give_amount.clone(),
+            };
+
+            (receipt, give_amount, take_amount)
+        }
+    };
+
+    let quoted_price = quote::price(&quote.give_amount, &quote.take_amount);
+    let realised_price = quote::price(&give_amount, &take_amount);
+
+    let history = &mut get_state_mut().history;
+    let record = TradeRecord {
+        id: history.next_id(),
+        origin,
+        give_currency: order.give_currency,
+        take_currency: order.take_currency,
+        give_amount,
+        take_amount,
+        path: route.path,
+        quoted_price: quoted_price.to_f64().unwrap() * decimals_modifier,
+        realised_price: realised_price.to_f64().unwrap() * decimals_modifier,
+        timestamp: get_env().time(),
+        sonic_receipt,
+        mode: state.mode,
+    };
+    history.push(record.clone());
+
+    Ok(record)
+}
+
+// -------------------- STATE ---------------------
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct State {
+    pub config: BotConfig,
+    pub reservations: ReservationLedger,
+    pub default_limits: TradingLimits,
+    pub tokens: TokenRegistry,
+    pub history: TradeHistory,
+    pub trailing_peaks: HashMap<TaskId, f64>,
+    pub oco_groups: OcoGroups,
+    pub check_counts: HashMap<TaskId, u64>,
+    pub order_statuses: HashMap<TaskId, OrderStatus>,
+    pub failed_attempts: HashMap<TaskId, u64>,
+    pub progress: HashMap<TaskId, OrderProgress>,
+    pub grids: Grids,
+    pub arbitrage: Arbitrage,
+    pub lp_book: LpBook,
+    pub rebalancer: Rebalancer,
+    pub strategies: StrategyRegistry,
+    pub mode: Mode,
+    pub paper: PaperAccount,
+}
+
+impl State {
+    pub fn new(config: BotConfig) -> Self {
+        let mut tokens = TokenRegistry::default();
+        tokens.set_well_known(&config);
+
+        State {
+            config,
+            reservations: ReservationLedger::default(),
+            default_limits: TradingLimits::default(),
+            tokens,
+            history: TradeHistory::default(),
+            trailing_peaks: HashMap::new(),
+            oco_groups: OcoGroups::default(),
+            check_counts: HashMap::new(),
+            order_statuses: HashMap::new(),
+            failed_attempts: HashMap::new(),
+            progress: HashMap::new(),
+            grids: Grids::default(),
+            arbitrage: Arbitrage::default(),
+            lp_book: LpBook::default(),
+            rebalancer: Rebalancer::default(),
+            strategies: StrategyRegistry::default(),
+            mode: Mode::Live,
+            paper: PaperAccount::default(),
+        }
+    }
+}
+
+pub static mut STATE: Option<State> = None;
+
+pub fn get_state() -> &'static State {
+    unsafe { STATE.as_ref().unwrap() }
+}
+
+pub fn get_state_mut() -> &'static mut State {
+    unsafe { STATE.as_mut().unwrap() }
+}
+
+#[init]
+pub fn init(config: Option<BotConfig>) {
+    // without a config the bot trades on mainnet and is controlled by whoever deployed it
+    let config = config.unwrap_or_else(|| BotConfig::mainnet(caller()));
+
+    unsafe { STATE = Some(State::new(config)) }
+}
+
+#[pre_upgrade]
+pub fn pre_upgrade_hook() {
+    stable_save((get_state().clone(), get_cron_state().clone()))
+        .expect("Unable to save the state to stable memory");
+}
+
+#[post_upgrade]
+pub fn post_upgrade_hook() {
+    let (state, cron_state) = match stable_restore::<(State, TaskScheduler)>() {
+        Ok(it) => it,
+        Err(_) => {
+            let (legacy_state, mut cron_state): (LegacyState, TaskScheduler) =
+                stable_restore().expect("Unable to restore the state from stable memory");
+            let state = migrate_legacy_state(legacy_state, &mut cron_state);
+
+            (state, cron_state)
+        }
+    };
+
+    unsafe { STATE = Some(state) }
+    set_cron_state(cron_state);
+}
+
+/// Rewrites the limit orders of the first version into the current format, so they keep being
+/// checked, and reserves what they are going to give. A TakeExact order can't be quoted during
+/// the upgrade, so it reserves nothing and is only checked against the balance when it fills.
+pub fn migrate_legacy_state(legacy_state: LegacyState, cron_state: &mut TaskScheduler) -> State {
+    let mut state = State::new(legacy_state.config());
+
+    for task in cron_state.tasks.values_mut() {
+        let order = match task.get_payload::<LegacyLimitOrder>() {
+            Ok(legacy_order) => legacy_order.migrate(),
+            Err(_) => continue,
+        };
+
+        let market_order = order.market_order();
+        if let OrderDirective::GiveExact(give_amount) = &market_order.directive {
+            state.reservations.reserve(
+                task.id,
+                market_order.give_currency.clone(),
+                give_amount.clone(),
+            );
+        }
+
+        task.scheduling_interval = scheduling_interval(&order);
+        task.payload = Task {
+            data: encode_one(order).expect("Unable to encode a migrated order"),
+        };
+    }
+
+    state
 }
 
 implement_cron!();
@@ -349,7 +2440,10 @@ implement_cron!();
 
 export_service!();
 
-#[query(name = "__get_candid_interface_tmp_hack")]
+#[cfg_attr(
+    target_arch = "wasm32",
+    query(name = "__get_candid_interface_tmp_hack")
+)]
 fn export_candid() -> String {
     __export_service()
 }
@@ -0,0 +1,437 @@
+use crate::input::{Scenario, Snapshot};
+use crate::market::SimulatedSonic;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use ic_cdk::export::candid::{Int, Nat};
+use ic_cron_sonic_bot_example::common::quote::{to_decimal, BPS};
+use ic_cron_sonic_bot_example::common::registry::TokenRegistry;
+use ic_cron_sonic_bot_example::common::strategy::{StrategyAction, StrategyRegistry};
+use ic_cron_sonic_bot_example::common::types::{
+    Currency, OrderDirective, OrderStatus, ScheduledOrder, TokenMetadata, TradeOrigin, TradeRecord,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+pub struct Fill {
+    pub id: u64,
+    pub source: String,
+    pub timestamp: u64,
+    pub give_currency: Currency,
+    pub take_currency: Currency,
+    pub give_amount: String,
+    pub take_amount: String,
+    pub path: Vec<Currency>,
+    pub price: f64,
+}
+
+#[derive(Serialize)]
+pub struct OrderResult {
+    pub id: u64,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ActionFailure {
+    pub timestamp: u64,
+    pub source: String,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct EquityPoint {
+    pub timestamp: u64,
+    pub value: String,
+}
+
+/// Amounts are strings, JSON numbers can't hold them without losing precision
+#[derive(Serialize)]
+pub struct Report {
+    pub quote_currency: Currency,
+    pub start_value: String,
+    pub end_value: String,
+    pub pnl: String,
+    pub pnl_bps: i64,
+    pub max_drawdown_bps: u64,
+    pub final_balances: BTreeMap<Currency, String>,
+    pub fills: Vec<Fill>,
+    pub orders: Vec<OrderResult>,
+    pub failures: Vec<ActionFailure>,
+    pub equity: Vec<EquityPoint>,
+}
+
+struct BacktestOrder {
+    order: ScheduledOrder,
+    status: OrderStatus,
+    error: Option<String>,
+    checks_made: u64,
+    peak: Option<f64>,
+}
+
+/// Orders are checked once per snapshot, strategies run as often as their interval allows.
+/// Only orders triggered by price can be backtested.
+pub fn run(scenario: Scenario, snapshots: Vec<Snapshot>) -> Result<Report, String> {
+    let start = snapshots[0].timestamp;
+    scenario
+        .default_limits
+        .validate()
+        .map_err(|e| format!("Invalid default limits: {:?}", e))?;
+
+    let mut tokens = TokenRegistry::default();
+    for token in scenario.tokens {
+        let metadata = TokenMetadata {
+            name: token.symbol.clone(),
+            decimals: token.decimals,
+            fee: Nat::from(0),
+        };
+
+        tokens
+            .register(token.symbol, token.canister_id, Some(metadata))
+            .map_err(|e| format!("Invalid token: {:?}", e))?;
+    }
+    tokens
+        .get(&scenario.quote_currency)
+        .map_err(|e| format!("Invalid quote currency: {:?}", e))?;
+
+    let mut orders = Vec::new();
+    for (id, order) in scenario.orders.into_iter().enumerate() {
+        if matches!(
+            order,
+            ScheduledOrder::Twap(_) | ScheduledOrder::Recurring(_)
+        ) {
+            return Err(format!("Order {} is not triggered by price", id));
+        }
+
+        order
+            .validate(&scenario.default_limits, start)
+            .map_err(|e| format!("Invalid order {}: {:?}", id, e))?;
+
+        orders.push(BacktestOrder {
+            order,
+            status: OrderStatus::Pending,
+            error: None,
+            checks_made: 0,
+            peak: None,
+        });
+    }
+
+    let mut strategies = StrategyRegistry::default();
+    for (i, strategy) in scenario.strategies.into_iter().enumerate() {
+        strategy
+            .config
+            .validate(&scenario.default_limits)
+            .map_err(|e| format!("Invalid strategy {}: {:?}", i, e))?;
+
+        strategies.add(strategy.config.build(), strategy.interval_secs, start);
+    }
+
+    let mut sonic = SimulatedSonic::new(tokens, scenario.balances);
+    let mut fills: Vec<Fill> = Vec::new();
+    let mut failures = Vec::new();
+    let mut start_value = None;
+    let mut equity = Vec::new();
+
+    for snapshot in snapshots {
+        let now = snapshot.timestamp;
+        sonic.set_pairs(snapshot.pairs);
+
+        // the starting funds are valued before anything is traded
+        if start_value.is_none() {
+            start_value = Some(portfolio_value(&sonic, &scenario.quote_currency));
+        }
+
+        for (id, it) in orders.iter_mut().enumerate() {
+            if !matches!(it.status, OrderStatus::Pending) {
+                continue;
+            }
+
+            if let ScheduledOrder::Limit(limit_order) = &it.order {
+                if limit_order.time_in_force().is_expired(now, it.checks_made) {
+                    it.status = OrderStatus::Expired;
+                    continue;
+                }
+                it.checks_made += 1;
+            }
+
+            let market_order = it.order.market_order().clone();
+            let market = sonic.snapshot(now);
+            // like with a failed call, the order is checked again next time
+            let price = match market.quoted_price(&market_order) {
+                Some(price) => price,
+                None => continue,
+            };
+
+            if !it.order.is_triggered(price, &mut it.peak) {
+                continue;
+            }
+
+            let limits = market_order.limits(&scenario.default_limits);
+            let origin = it.order.trade_origin(id as u64);
+            match sonic.swap(&market_order, limits.max_price_impact_bps, origin, now) {
+                Ok(record) => {
+                    it.status = OrderStatus::Filled;
+                    fills.push(fill(fills.len() as u64, format!("order {}", id), &record));
+                }
+                Err(e) => {
+                    it.status = OrderStatus::Failed;
+                    it.error = Some(format!("{:?}", e));
+                }
+            }
+        }
+
+        for strategy_id in strategies.take_due(now) {
+            let market = sonic.snapshot(now);
+            let instance = strategies.get_mut(strategy_id).unwrap();
+            let actions = instance.kind.strategy_mut().on_tick(&market);
+
+            for action in actions {
+                let result = match &action {
+                    StrategyAction::Swap(order) => {
+                        let limits = order.limits(&scenario.default_limits);
+                        let origin = TradeOrigin::Strategy(strategy_id);
+
+                        sonic.swap(order, limits.max_price_impact_bps, origin, now)
+                    }
+                };
+
+                let source = format!("strategy {}", strategy_id);
+                match &result {
+                    Ok(record) => fills.push(fill(fills.len() as u64, source, record)),
+                    Err(e) => failures.push(ActionFailure {
+                        timestamp: now,
+                        source,
+                        error: format!("{:?}", e),
+                    }),
+                }
+
+                instance
+                    .kind
+                    .strategy_mut()
+                    .on_action_result(&action, result.as_ref().map_err(|e| format!("{:?}", e)));
+            }
+        }
+
+        equity.push((now, portfolio_value(&sonic, &scenario.quote_currency)));
+    }
+
+    let start_value = start_value.unwrap();
+    let end_value = equity[equity.len() - 1].1.clone();
+
+    let pnl_bps = pnl_bps(&start_value, &end_value)?;
+    let max_drawdown_bps = max_drawdown_bps(&start_value, &equity);
+
+    Ok(Report {
+        quote_currency: scenario.quote_currency,
+        pnl: (Int::from(end_value.clone()) - Int::from(start_value.clone()))
+            .0
+            .to_string(),
+        start_value: start_value.0.to_string(),
+        end_value: end_value.0.to_string(),
+        pnl_bps,
+        max_drawdown_bps,
+        final_balances: sonic
+            .balances
+            .iter()
+            .map(|(currency, balance)| (currency.clone(), balance.0.to_string()))
+            .collect(),
+        fills,
+        orders: orders
+            .into_iter()
+            .enumerate()
+            .map(|(id, it)| OrderResult {
+                id: id as u64,
+                status: format!("{:?}", it.status),
+                error: it.error,
+            })
+            .collect(),
+        failures,
+        equity: equity
+            .into_iter()
+            .map(|(timestamp, value)| EquityPoint {
+                timestamp,
+                value: value.0.to_string(),
+            })
+            .collect(),
+    })
+}
+
+/// How much the portfolio gained or lost against its starting value
+fn pnl_bps(start_value: &Nat, end_value: &Nat) -> Result<i64, String> {
+    if start_value.0 == 0u64.into() {
+        return Ok(0);
+    }
+
+    let ratio = to_decimal(end_value) / to_decimal(start_value);
+
+    ((ratio - BigDecimal::from(1)) * BigDecimal::from(BPS))
+        .to_i64()
+        .ok_or_else(|| String::from("PnL is too large to express in bps"))
+}
+
+/// The deepest fall from a previous peak, the starting value counts as the first one
+fn max_drawdown_bps(start_value: &Nat, equity: &[(u64, Nat)]) -> u64 {
+    let mut peak = start_value.clone();
+    let mut max_drawdown_bps = 0;
+
+    for (_, value) in equity {
+        if *value > peak {
+            peak = value.clone();
+        } else if *value < peak {
+            let drawdown = (peak.clone() - value.clone()) * BPS / peak.clone();
+            // never more than everything
+            max_drawdown_bps = max_drawdown_bps.max(drawdown.0.to_u64().unwrap_or(BPS));
+        }
+    }
+
+    max_drawdown_bps
+}
+
+/// What all the balances would sell for in the quote currency, like the rebalancer values them
+fn portfolio_value(sonic: &SimulatedSonic, quote_currency: &Currency) -> Nat {
+    let pools = sonic.pools();
+
+    sonic
+        .balances
+        .iter()
+        .fold(Nat::from(0), |sum, (currency, balance)| {
+            if currency == quote_currency {
+                return sum + balance.clone();
+            }
+
+            match pools.best_route(
+                currency,
+                quote_currency,
+                &OrderDirective::GiveExact(balance.clone()),
+            ) {
+                Ok(route) => sum + route.quote.take_amount,
+                Err(_) => sum,
+            }
+        })
+}
+
+fn fill(id: u64, source: String, record: &TradeRecord) -> Fill {
+    Fill {
+        id,
+        source,
+        timestamp: record.timestamp,
+        give_currency: record.give_currency.clone(),
+        take_currency: record.take_currency.clone(),
+        give_amount: record.give_amount.0.to_string(),
+        take_amount: record.take_amount.0.to_string(),
+        path: record.path.clone(),
+        price: record.realised_price,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::ScenarioToken;
+    use ic_cdk::export::candid::Principal;
+    use ic_cron_sonic_bot_example::clients::sonic::SonicPairInfo;
+    use ic_cron_sonic_bot_example::common::types::{LimitOrder, MarketOrder, TargetPrice};
+    use std::collections::HashMap;
+
+    fn token(symbol: &str, id: u8) -> ScenarioToken {
+        ScenarioToken {
+            symbol: String::from(symbol),
+            canister_id: Principal::from_slice(&[id]),
+            decimals: 8,
+        }
+    }
+
+    fn snapshot(timestamp: u64, xtc_reserve: u64, wicp_reserve: u64) -> Snapshot {
+        let xtc = Principal::from_slice(&[1]).to_text();
+        let wicp = Principal::from_slice(&[2]).to_text();
+
+        Snapshot {
+            timestamp,
+            pairs: vec![SonicPairInfo {
+                id: format!("{}:{}", xtc, wicp),
+                token0: xtc,
+                token1: wicp,
+                creator: Principal::anonymous(),
+                reserve0: Nat::from(xtc_reserve),
+                reserve1: Nat::from(wicp_reserve),
+                price0CumulativeLast: Nat::from(0),
+                price1CumulativeLast: Nat::from(0),
+                kLast: Nat::from(0),
+                blockTimestampLast: Int::from(0),
+                totalSupply: Nat::from(0),
+                lptoken: String::new(),
+            }],
+        }
+    }
+
+    /// Buys XTC for 10% of the WICP once it gets cheaper than 0.9 WICP
+    fn scenario() -> Scenario {
+        let mut balances = HashMap::new();
+        balances.insert(String::from("WICP"), Nat::from(10_000));
+
+        Scenario {
+            tokens: vec![token("XTC", 1), token("WICP", 2)],
+            quote_currency: String::from("WICP"),
+            balances,
+            default_limits: Default::default(),
+            orders: vec![ScheduledOrder::Limit(LimitOrder {
+                target_price_condition: TargetPrice::LessThan(0.9),
+                market_order: MarketOrder {
+                    give_currency: String::from("WICP"),
+                    take_currency: String::from("XTC"),
+                    directive: OrderDirective::GiveExact(Nat::from(1_000)),
+                    max_slippage_bps: None,
+                    deadline_secs: None,
+                    max_price_impact_bps: None,
+                },
+                time_in_force: None,
+            })],
+            strategies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn limit_order_fills_along_the_pool_curve() {
+        let snapshots = vec![
+            snapshot(0, 1_000_000, 1_000_000),
+            snapshot(60, 2_000_000, 1_000_000),
+        ];
+
+        let report = run(scenario(), snapshots).unwrap();
+
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].timestamp, 60);
+        assert_eq!(report.orders[0].status, "Filled");
+        assert_eq!(report.final_balances["WICP"], "9000");
+
+        // the fee and the order's own price impact make it worse than the spot price of 0.5
+        let xtc: u64 = report.final_balances["XTC"].parse().unwrap();
+        assert!(xtc < 2_000);
+        assert!(report.fills[0].price > 0.5);
+
+        assert_eq!(report.start_value, "10000");
+        assert!(report.pnl_bps < 0);
+        assert!(report.max_drawdown_bps > 0);
+    }
+
+    #[test]
+    fn pnl_is_relative_to_the_starting_value() {
+        assert_eq!(pnl_bps(&Nat::from(1_000), &Nat::from(1_100)), Ok(1_000));
+        assert_eq!(pnl_bps(&Nat::from(1_000), &Nat::from(900)), Ok(-1_000));
+        assert_eq!(pnl_bps(&Nat::from(0), &Nat::from(900)), Ok(0));
+
+        let huge = Nat::from(u64::MAX) * Nat::from(u64::MAX);
+        assert!(pnl_bps(&Nat::from(1), &huge).is_err());
+    }
+
+    #[test]
+    fn drawdown_is_measured_from_the_highest_peak() {
+        let equity: Vec<(u64, Nat)> = [1_000u64, 1_200, 900, 1_100, 1_000]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (i as u64, Nat::from(*value)))
+            .collect();
+
+        assert_eq!(max_drawdown_bps(&Nat::from(1_000), &equity), 2_500);
+        assert_eq!(max_drawdown_bps(&Nat::from(1_000), &equity[..2]), 0);
+    }
+}
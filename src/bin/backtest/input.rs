@@ -0,0 +1,118 @@
+use ic_cdk::export::candid::{Int, Nat, Principal};
+use ic_cron_sonic_bot_example::clients::sonic::SonicPairInfo;
+use ic_cron_sonic_bot_example::common::types::{
+    Currency, ScheduledOrder, StrategyConfig, TradingLimits,
+};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+#[derive(Deserialize)]
+pub struct ScenarioToken {
+    pub symbol: Currency,
+    pub canister_id: Principal,
+    pub decimals: u8,
+}
+
+#[derive(Deserialize)]
+pub struct ScenarioStrategy {
+    pub config: StrategyConfig,
+    pub interval_secs: u64,
+}
+
+/// What is tested, with which funds. The portfolio is valued in `quote_currency`.
+#[derive(Deserialize)]
+pub struct Scenario {
+    pub tokens: Vec<ScenarioToken>,
+    pub quote_currency: Currency,
+    pub balances: HashMap<Currency, Nat>,
+    #[serde(default)]
+    pub default_limits: TradingLimits,
+    #[serde(default)]
+    pub orders: Vec<ScheduledOrder>,
+    #[serde(default)]
+    pub strategies: Vec<ScenarioStrategy>,
+}
+
+/// All Sonic pools at one moment
+#[derive(Deserialize)]
+pub struct Snapshot {
+    pub timestamp: u64,
+    pub pairs: Vec<SonicPairInfo>,
+}
+
+#[derive(Deserialize)]
+struct ReserveRow {
+    timestamp: u64,
+    token0: String,
+    token1: String,
+    // reserves don't fit into any primitive number, so they are parsed by hand
+    reserve0: String,
+    reserve1: String,
+}
+
+pub fn load_scenario(path: &str) -> Result<Scenario, String> {
+    let file = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+
+    serde_json::from_str(&file).map_err(|e| format!("Invalid scenario {}: {}", path, e))
+}
+
+/// Snapshots in the order they were taken
+pub fn load_snapshots(path: &str) -> Result<Vec<Snapshot>, String> {
+    let mut snapshots = if path.ends_with(".csv") {
+        load_csv_snapshots(path)?
+    } else {
+        let file =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+
+        serde_json::from_str(&file).map_err(|e| format!("Invalid snapshots {}: {}", path, e))?
+    };
+
+    if snapshots.is_empty() {
+        return Err(format!("There are no snapshots in {}", path));
+    }
+    snapshots.sort_by_key(|it| it.timestamp);
+
+    Ok(snapshots)
+}
+
+/// Rows of one timestamp make up a snapshot. CSV has room for reserves only, so the rest of the
+/// pair info is left empty.
+fn load_csv_snapshots(path: &str) -> Result<Vec<Snapshot>, String> {
+    let mut reader =
+        csv::Reader::from_path(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    let mut pairs: BTreeMap<u64, Vec<SonicPairInfo>> = BTreeMap::new();
+
+    for row in reader.deserialize() {
+        let row: ReserveRow = row.map_err(|e| format!("Invalid snapshots {}: {}", path, e))?;
+
+        let pair = SonicPairInfo {
+            id: format!("{}:{}", row.token0, row.token1),
+            token0: row.token0,
+            token1: row.token1,
+            creator: Principal::anonymous(),
+            reserve0: parse_nat(&row.reserve0)?,
+            reserve1: parse_nat(&row.reserve1)?,
+            price0CumulativeLast: Nat::from(0),
+            price1CumulativeLast: Nat::from(0),
+            kLast: Nat::from(0),
+            blockTimestampLast: Int::from(0),
+            totalSupply: Nat::from(0),
+            lptoken: String::new(),
+        };
+
+        pairs.entry(row.timestamp).or_default().push(pair);
+    }
+
+    Ok(pairs
+        .into_iter()
+        .map(|(timestamp, pairs)| Snapshot { timestamp, pairs })
+        .collect())
+}
+
+fn parse_nat(value: &str) -> Result<Nat, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{} is not a valid reserve", value))
+}
@@ -0,0 +1,40 @@
+//! Replays recorded Sonic reserves through the same order and strategy logic the canister runs,
+//! with a simulated Sonic filling the trades, to see how they would have done before real funds
+//! are put in.
+//!
+//! ```text
+//! cargo run --features backtest --bin backtest -- <scenario.json> <snapshots.json|snapshots.csv>
+//! ```
+//!
+//! The scenario lists the tokens, the starting balances and the orders and strategies to test.
+//! Snapshots are either a JSON list of `{ "timestamp": .., "pairs": [SonicPairInfo, ..] }`, or a
+//! CSV with `timestamp,token0,token1,reserve0,reserve1` rows. The report with every fill, the PnL
+//! and the maximum drawdown is printed as JSON.
+
+mod engine;
+mod input;
+mod market;
+
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: backtest <scenario.json> <snapshots.json|snapshots.csv>");
+        exit(2);
+    }
+
+    let report = input::load_scenario(&args[1]).and_then(|scenario| {
+        let snapshots = input::load_snapshots(&args[2])?;
+
+        engine::run(scenario, snapshots)
+    });
+
+    match report {
+        Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
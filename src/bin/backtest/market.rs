@@ -0,0 +1,188 @@
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::Nat;
+use ic_cron_sonic_bot_example::clients::sonic::SonicPairInfo;
+use ic_cron_sonic_bot_example::common::errors::BotError;
+use ic_cron_sonic_bot_example::common::quote;
+use ic_cron_sonic_bot_example::common::registry::TokenRegistry;
+use ic_cron_sonic_bot_example::common::router::PoolGraph;
+use ic_cron_sonic_bot_example::common::strategy::MarketSnapshot;
+use ic_cron_sonic_bot_example::common::types::{
+    Currency, MarketOrder, Mode, OrderDirective, TradeOrigin, TradeRecord,
+};
+use std::collections::HashMap;
+
+/// Stands in for Sonic during a replay. Each recorded snapshot replaces the pools, and until the
+/// next one they are moved by the bot's own trades, the way the real ones would be.
+pub struct SimulatedSonic {
+    tokens: TokenRegistry,
+    pairs: Vec<SonicPairInfo>,
+    pub balances: HashMap<Currency, Nat>,
+}
+
+impl SimulatedSonic {
+    pub fn new(tokens: TokenRegistry, balances: HashMap<Currency, Nat>) -> Self {
+        SimulatedSonic {
+            tokens,
+            pairs: Vec::new(),
+            balances,
+        }
+    }
+
+    pub fn set_pairs(&mut self, pairs: Vec<SonicPairInfo>) {
+        self.pairs = pairs;
+    }
+
+    pub fn snapshot(&self, timestamp: u64) -> MarketSnapshot {
+        let decimals = self
+            .tokens
+            .list()
+            .into_iter()
+            .filter_map(|it| Some((it.symbol, it.metadata?.decimals)))
+            .collect();
+
+        MarketSnapshot {
+            timestamp,
+            pools: self.pools(),
+            balances: self.balances.clone(),
+            decimals,
+        }
+    }
+
+    pub fn pools(&self) -> PoolGraph {
+        PoolGraph::new(self.pairs.clone(), &self.tokens)
+    }
+
+    pub fn balance(&self, currency: &Currency) -> Nat {
+        self.balances
+            .get(currency)
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0))
+    }
+
+    /// Fills the order like `execute_market_order` would, with the same route and price impact
+    /// check. Every hop goes along the constant product curve of `quote`, so the fee and the
+    /// order's own price impact are paid, and the pools stay moved until the next snapshot.
+    ///
+    /// What is not modelled is everyone else: nobody trades between the quote and the swap, so
+    /// the fill always matches the quote and `max_slippage_bps` never kicks in. Fills of orders
+    /// that are large next to the flow between two snapshots come out better than they would be.
+    pub fn swap(
+        &mut self,
+        order: &MarketOrder,
+        max_price_impact_bps: u64,
+        origin: TradeOrigin,
+        timestamp: u64,
+    ) -> Result<TradeRecord, BotError> {
+        let snapshot = self.snapshot(timestamp);
+        let route = snapshot.pools.best_route(
+            &order.give_currency,
+            &order.take_currency,
+            &order.directive,
+        )?;
+
+        if route.quote.price_impact_bps > max_price_impact_bps {
+            return Err(BotError::PriceImpactTooHigh {
+                price_impact_bps: route.quote.price_impact_bps,
+                max_price_impact_bps,
+            });
+        }
+
+        let available = self.balance(&order.give_currency);
+        if route.quote.give_amount > available {
+            return Err(BotError::InsufficientBalance {
+                currency: order.give_currency.clone(),
+                required: route.quote.give_amount,
+                available,
+            });
+        }
+
+        // what goes in and comes out of each pool on the way
+        let mut amounts = match &order.directive {
+            OrderDirective::GiveExact(give_amount) => {
+                let mut amounts = vec![give_amount.clone()];
+                for (reserve_in, reserve_out) in &route.reserves {
+                    let amount_in = amounts.last().unwrap();
+                    let amount_out = quote::get_amount_out(amount_in, reserve_in, reserve_out)
+                        .ok_or(BotError::InsufficientLiquidity)?;
+                    amounts.push(amount_out);
+                }
+
+                amounts
+            }
+            OrderDirective::TakeExact(take_amount) => {
+                let mut amounts = vec![take_amount.clone()];
+                for (reserve_in, reserve_out) in route.reserves.iter().rev() {
+                    let amount_out = amounts.last().unwrap();
+                    let amount_in = quote::get_amount_in(amount_out, reserve_in, reserve_out)
+                        .ok_or(BotError::InsufficientLiquidity)?;
+                    amounts.push(amount_in);
+                }
+                amounts.reverse();
+
+                amounts
+            }
+        };
+
+        for (i, hop) in route.path.windows(2).enumerate() {
+            self.move_reserves(&hop[0], &hop[1], &amounts[i], &amounts[i + 1]);
+        }
+
+        let take_amount = amounts.pop().unwrap();
+        let give_amount = amounts.swap_remove(0);
+
+        let give_balance = self
+            .balances
+            .entry(order.give_currency.clone())
+            .or_default();
+        *give_balance = give_balance.clone() - give_amount.clone();
+        *self
+            .balances
+            .entry(order.take_currency.clone())
+            .or_default() += take_amount.clone();
+
+        let price = quote::price(&give_amount, &take_amount).to_f64().unwrap()
+            * snapshot
+                .decimals_modifier(&order.give_currency, &order.take_currency)
+                .unwrap_or(1f64);
+
+        Ok(TradeRecord {
+            id: 0,
+            origin,
+            give_currency: order.give_currency.clone(),
+            take_currency: order.take_currency.clone(),
+            give_amount,
+            take_amount,
+            path: route.path,
+            quoted_price: price,
+            realised_price: price,
+            timestamp,
+            sonic_receipt: Nat::from(0),
+            mode: Mode::Paper,
+        })
+    }
+
+    fn move_reserves(
+        &mut self,
+        give: &Currency,
+        take: &Currency,
+        amount_in: &Nat,
+        amount_out: &Nat,
+    ) {
+        let give_token = self.tokens.canister_id(give).unwrap().to_text();
+        let take_token = self.tokens.canister_id(take).unwrap().to_text();
+
+        for pair in &mut self.pairs {
+            if pair.token0 == give_token && pair.token1 == take_token {
+                pair.reserve0 += amount_in.clone();
+                pair.reserve1 = pair.reserve1.clone() - amount_out.clone();
+            } else if pair.token0 == take_token && pair.token1 == give_token {
+                pair.reserve1 += amount_in.clone();
+                pair.reserve0 = pair.reserve0.clone() - amount_out.clone();
+            } else {
+                continue;
+            }
+
+            return;
+        }
+    }
+}
@@ -0,0 +1,904 @@
+//! In-memory stand-ins for the canisters the bot calls, so the order logic can be tested without
+//! a replica. Methods the bot doesn't use are rejected.
+
+use crate::clients::dip20::{Dip20, Dip20Metadata, Dip20TokenInfo, Dip20TxError, Dip20TxReceipt};
+use crate::clients::sonic::{
+    MotokoResult, Sonic, SonicPairInfo, SonicSwapInfo, SonicTokenInfo, SonicTxReceipt,
+    SonicUserInfo,
+};
+use crate::clients::xtc::{XTCBurnError, XTCBurnPayload, XTCBurnResult, XTCMintResult, XTC};
+use crate::common::quote;
+use async_trait::async_trait;
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_cdk::export::candid::{Int, Nat, Principal};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Only reached through the methods the bot never calls
+#[allow(dead_code)]
+fn unsupported<T>(method: &str) -> CallResult<T> {
+    Err((
+        RejectionCode::CanisterError,
+        format!("{} is not supported by the fake", method),
+    ))
+}
+
+/// Calls a test wants to fail, like they would on a stopped or broken canister
+#[derive(Default)]
+struct Rejections {
+    all: AtomicBool,
+    methods: Mutex<HashSet<&'static str>>,
+}
+
+impl Rejections {
+    fn check(&self, method: &'static str) -> CallResult<()> {
+        if self.all.load(Ordering::SeqCst) || self.methods.lock().unwrap().contains(method) {
+            return Err((
+                RejectionCode::CanisterError,
+                format!("{} is rejected", method),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Accounts {
+    balances: HashMap<Principal, Nat>,
+    allowances: HashMap<(Principal, Principal), Nat>,
+    tx_count: u64,
+}
+
+/// A DIP20 token canister without fees
+pub struct FakeLedger {
+    pub canister_id: Principal,
+    pub symbol: String,
+    pub decimals: u8,
+    accounts: Mutex<Accounts>,
+    rejections: Rejections,
+}
+
+impl FakeLedger {
+    pub fn new(canister_id: Principal, symbol: &str, decimals: u8) -> Self {
+        FakeLedger {
+            canister_id,
+            symbol: String::from(symbol),
+            decimals,
+            accounts: Mutex::new(Accounts::default()),
+            rejections: Rejections::default(),
+        }
+    }
+
+    pub fn balance(&self, who: Principal) -> Nat {
+        let accounts = self.accounts.lock().unwrap();
+
+        accounts.balances.get(&who).cloned().unwrap_or_default()
+    }
+
+    pub fn total_supply(&self) -> Nat {
+        let accounts = self.accounts.lock().unwrap();
+
+        accounts
+            .balances
+            .values()
+            .fold(Nat::from(0), |sum, it| sum + it.clone())
+    }
+
+    pub fn mint_to(&self, to: Principal, amount: Nat) {
+        let mut accounts = self.accounts.lock().unwrap();
+
+        *accounts.balances.entry(to).or_default() += amount;
+    }
+
+    /// Every following call is rejected
+    pub fn stop(&self) {
+        self.rejections.all.store(true, Ordering::SeqCst);
+    }
+
+    /// The token as seen by the canister calling it
+    pub fn called_by(&self, caller: Principal) -> FakeDip20<'_> {
+        FakeDip20 {
+            ledger: self,
+            caller,
+        }
+    }
+
+    fn transfer(&self, from: Principal, to: Principal, value: Nat) -> Dip20TxReceipt {
+        let mut accounts = self.accounts.lock().unwrap();
+
+        let balance = accounts.balances.get(&from).cloned().unwrap_or_default();
+        if value > balance {
+            return Err(Dip20TxError::InsufficientBalance);
+        }
+
+        accounts.balances.insert(from, balance - value.clone());
+        *accounts.balances.entry(to).or_default() += value;
+        accounts.tx_count += 1;
+
+        Ok(Nat::from(accounts.tx_count))
+    }
+
+    fn burn(&self, from: Principal, value: Nat) -> Dip20TxReceipt {
+        let mut accounts = self.accounts.lock().unwrap();
+
+        let balance = accounts.balances.get(&from).cloned().unwrap_or_default();
+        if value > balance {
+            return Err(Dip20TxError::InsufficientBalance);
+        }
+
+        accounts.balances.insert(from, balance - value);
+        accounts.tx_count += 1;
+
+        Ok(Nat::from(accounts.tx_count))
+    }
+
+    fn transfer_from(
+        &self,
+        spender: Principal,
+        from: Principal,
+        to: Principal,
+        value: Nat,
+    ) -> Dip20TxReceipt {
+        {
+            let mut accounts = self.accounts.lock().unwrap();
+
+            let allowance = accounts
+                .allowances
+                .get(&(from, spender))
+                .cloned()
+                .unwrap_or_default();
+            if value > allowance {
+                return Err(Dip20TxError::InsufficientAllowance);
+            }
+
+            let balance = accounts.balances.get(&from).cloned().unwrap_or_default();
+            if value > balance {
+                return Err(Dip20TxError::InsufficientBalance);
+            }
+
+            accounts
+                .allowances
+                .insert((from, spender), allowance - value.clone());
+        }
+
+        self.transfer(from, to, value)
+    }
+}
+
+pub struct FakeDip20<'a> {
+    ledger: &'a FakeLedger,
+    caller: Principal,
+}
+
+#[async_trait]
+impl<'a> Dip20 for FakeDip20<'a> {
+    async fn transfer(&self, to: Principal, value: Nat) -> CallResult<(Dip20TxReceipt,)> {
+        self.ledger.rejections.check("transfer")?;
+
+        Ok((self.ledger.transfer(self.caller, to, value),))
+    }
+
+    async fn transfer_from(
+        &self,
+        from: Principal,
+        to: Principal,
+        value: Nat,
+    ) -> CallResult<(Dip20TxReceipt,)> {
+        self.ledger.rejections.check("transferFrom")?;
+
+        Ok((self.ledger.transfer_from(self.caller, from, to, value),))
+    }
+
+    async fn approve(&self, spender: Principal, value: Nat) -> CallResult<(Dip20TxReceipt,)> {
+        self.ledger.rejections.check("approve")?;
+        let mut accounts = self.ledger.accounts.lock().unwrap();
+
+        accounts.allowances.insert((self.caller, spender), value);
+        accounts.tx_count += 1;
+
+        Ok((Ok(Nat::from(accounts.tx_count)),))
+    }
+
+    async fn mint(&self, _to: Principal, _amount: Nat) -> CallResult<(Dip20TxReceipt,)> {
+        unsupported("mint")
+    }
+
+    async fn burn(&self, _amount: Nat) -> CallResult<(Dip20TxReceipt,)> {
+        unsupported("burn")
+    }
+
+    async fn set_name(&self, _name: String) -> CallResult<()> {
+        unsupported("setName")
+    }
+
+    async fn name(&self) -> CallResult<(String,)> {
+        self.ledger.rejections.check("name")?;
+
+        Ok((self.ledger.symbol.clone(),))
+    }
+
+    async fn set_logo(&self, _logo: String) -> CallResult<()> {
+        unsupported("setLogo")
+    }
+
+    async fn get_logo(&self) -> CallResult<(String,)> {
+        unsupported("getLogo")
+    }
+
+    async fn set_fee(&self, _fee: Nat) -> CallResult<()> {
+        unsupported("setFee")
+    }
+
+    async fn set_fee_to(&self, _fee_to: Nat) -> CallResult<()> {
+        unsupported("setFeeTo")
+    }
+
+    async fn set_owner(&self, _owner: Principal) -> CallResult<()> {
+        unsupported("setOwner")
+    }
+
+    async fn owner(&self) -> CallResult<(Principal,)> {
+        unsupported("owner")
+    }
+
+    async fn symbol(&self) -> CallResult<(String,)> {
+        self.ledger.rejections.check("symbol")?;
+
+        Ok((self.ledger.symbol.clone(),))
+    }
+
+    async fn decimals(&self) -> CallResult<(u8,)> {
+        self.ledger.rejections.check("decimals")?;
+
+        Ok((self.ledger.decimals,))
+    }
+
+    async fn total_supply(&self) -> CallResult<(Nat,)> {
+        self.ledger.rejections.check("totalSupply")?;
+
+        Ok((self.ledger.total_supply(),))
+    }
+
+    async fn balance_of(&self, id: Principal) -> CallResult<(Nat,)> {
+        self.ledger.rejections.check("balanceOf")?;
+
+        Ok((self.ledger.balance(id),))
+    }
+
+    async fn allowance(&self, owner: Principal, spender: Principal) -> CallResult<(Nat,)> {
+        self.ledger.rejections.check("allowance")?;
+        let accounts = self.ledger.accounts.lock().unwrap();
+
+        Ok((accounts
+            .allowances
+            .get(&(owner, spender))
+            .cloned()
+            .unwrap_or_default(),))
+    }
+
+    async fn get_metadata(&self) -> CallResult<(Dip20Metadata,)> {
+        self.ledger.rejections.check("getMetadata")?;
+
+        Ok((Dip20Metadata {
+            fee: Nat::from(0),
+            decimals: self.ledger.decimals,
+            owner: Principal::anonymous(),
+            logo: String::new(),
+            name: self.ledger.symbol.clone(),
+            totalSupply: self.ledger.total_supply(),
+            symbol: self.ledger.symbol.clone(),
+        },))
+    }
+
+    async fn history_size(&self) -> CallResult<(usize,)> {
+        unsupported("historySize")
+    }
+
+    async fn get_token_info(&self) -> CallResult<(Dip20TokenInfo,)> {
+        unsupported("getTokenInfo")
+    }
+
+    async fn get_holders(
+        &self,
+        _start: usize,
+        _limit: usize,
+    ) -> CallResult<(Vec<(Principal, Nat)>,)> {
+        unsupported("getHolders")
+    }
+
+    async fn get_allowance_size(&self) -> CallResult<(usize,)> {
+        unsupported("getAllowanceSize")
+    }
+
+    async fn get_user_approvals(&self, _who: Principal) -> CallResult<(Vec<(Principal, Nat)>,)> {
+        unsupported("getUserApprovals")
+    }
+}
+
+#[derive(Default)]
+struct SonicBook {
+    pairs: Vec<SonicPairInfo>,
+    balances: HashMap<(Principal, Principal), Nat>,
+    lp_balances: HashMap<(String, Principal), Nat>,
+    tx_count: u64,
+}
+
+impl SonicBook {
+    fn balance(&self, token: Principal, who: Principal) -> Nat {
+        self.balances
+            .get(&(token, who))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn lp_balances(&self, who: Principal) -> Vec<(String, Nat)> {
+        self.lp_balances
+            .iter()
+            .filter(|((_, owner), _)| *owner == who)
+            .map(|((pair_id, _), balance)| (pair_id.clone(), balance.clone()))
+            .collect()
+    }
+
+    fn pair_mut(&mut self, token_a: &str, token_b: &str) -> Option<&mut SonicPairInfo> {
+        self.pairs.iter_mut().find(|it| {
+            (it.token0 == token_a && it.token1 == token_b)
+                || (it.token0 == token_b && it.token1 == token_a)
+        })
+    }
+
+    /// Input and output reserves of every pool on the path
+    fn path_reserves(&self, path: &[String]) -> Option<Vec<(Nat, Nat)>> {
+        path.windows(2)
+            .map(|hop| {
+                let pair = self.pairs.iter().find(|it| {
+                    (it.token0 == hop[0] && it.token1 == hop[1])
+                        || (it.token0 == hop[1] && it.token1 == hop[0])
+                })?;
+
+                if pair.token0 == hop[0] {
+                    Some((pair.reserve0.clone(), pair.reserve1.clone()))
+                } else {
+                    Some((pair.reserve1.clone(), pair.reserve0.clone()))
+                }
+            })
+            .collect()
+    }
+
+    /// Takes `amount` of the token from the account, if there is enough of it
+    fn debit(&mut self, token: Principal, who: Principal, amount: &Nat) -> bool {
+        let balance = self.balance(token, who);
+        if *amount > balance {
+            return false;
+        }
+
+        self.balances.insert((token, who), balance - amount.clone());
+
+        true
+    }
+
+    fn next_tx(&mut self) -> SonicTxReceipt {
+        self.tx_count += 1;
+
+        MotokoResult::ok(Nat::from(self.tx_count))
+    }
+
+    /// Moves the amounts through the pools and settles them between the two accounts
+    fn settle_swap(
+        &mut self,
+        path: &[String],
+        amounts: &[Nat],
+        from: Principal,
+        to: Principal,
+    ) -> SonicTxReceipt {
+        let give_token = Principal::from_text(&path[0]).unwrap();
+        let take_token = Principal::from_text(&path[path.len() - 1]).unwrap();
+
+        if !self.debit(give_token, from, &amounts[0]) {
+            return MotokoResult::err(String::from("insufficient balance"));
+        }
+
+        for (i, hop) in path.windows(2).enumerate() {
+            let pair = self.pair_mut(&hop[0], &hop[1]).unwrap();
+
+            if pair.token0 == hop[0] {
+                pair.reserve0 += amounts[i].clone();
+                pair.reserve1 = pair.reserve1.clone() - amounts[i + 1].clone();
+            } else {
+                pair.reserve1 += amounts[i].clone();
+                pair.reserve0 = pair.reserve0.clone() - amounts[i + 1].clone();
+            }
+        }
+
+        *self.balances.entry((take_token, to)).or_default() += amounts[amounts.len() - 1].clone();
+
+        self.next_tx()
+    }
+}
+
+/// A Sonic swap canister with constant product pools and the same 0.3% fee, called by a single
+/// canister. Tokens are deposited into it and withdrawn from it through the fake ledgers.
+pub struct FakeSonic {
+    pub canister_id: Principal,
+    caller: Principal,
+    ledgers: HashMap<Principal, Arc<FakeLedger>>,
+    book: Mutex<SonicBook>,
+    rejections: Rejections,
+}
+
+impl FakeSonic {
+    pub fn new(canister_id: Principal, caller: Principal, ledgers: &[Arc<FakeLedger>]) -> Self {
+        FakeSonic {
+            canister_id,
+            caller,
+            ledgers: ledgers
+                .iter()
+                .map(|it| (it.canister_id, it.clone()))
+                .collect(),
+            book: Mutex::new(SonicBook::default()),
+            rejections: Rejections::default(),
+        }
+    }
+
+    /// Creates the pool or replaces its reserves. A new pool starts with LP tokens held by
+    /// somebody else, so the bot only ever owns what it adds.
+    pub fn set_reserves(&self, token0: Principal, token1: Principal, reserve0: Nat, reserve1: Nat) {
+        let mut book = self.book.lock().unwrap();
+        let (token0, token1) = (token0.to_text(), token1.to_text());
+
+        match book.pair_mut(&token0, &token1) {
+            Some(pair) if pair.token0 == token0 => {
+                pair.reserve0 = reserve0;
+                pair.reserve1 = reserve1;
+            }
+            Some(pair) => {
+                pair.reserve0 = reserve1;
+                pair.reserve1 = reserve0;
+            }
+            None => {
+                let id = format!("{}:{}", token0, token1);
+                let total_supply = Nat((reserve0.0.clone() * reserve1.0.clone()).sqrt());
+
+                book.pairs.push(SonicPairInfo {
+                    id: id.clone(),
+                    token0,
+                    token1,
+                    creator: Principal::anonymous(),
+                    reserve0,
+                    reserve1,
+                    price0CumulativeLast: Nat::from(0),
+                    price1CumulativeLast: Nat::from(0),
+                    kLast: Nat::from(0),
+                    blockTimestampLast: Int::from(0),
+                    totalSupply: total_supply,
+                    lptoken: id,
+                })
+            }
+        }
+    }
+
+    pub fn balance(&self, token: Principal, who: Principal) -> Nat {
+        self.book.lock().unwrap().balance(token, who)
+    }
+
+    pub fn credit(&self, token: Principal, who: Principal, amount: Nat) {
+        let mut book = self.book.lock().unwrap();
+
+        *book.balances.entry((token, who)).or_default() += amount;
+    }
+
+    /// Every following call of the method is rejected
+    pub fn reject(&self, method: &'static str) {
+        self.rejections.methods.lock().unwrap().insert(method);
+    }
+
+    /// Calls to the method go through again
+    pub fn accept(&self, method: &'static str) {
+        self.rejections.methods.lock().unwrap().remove(method);
+    }
+
+    /// Every following call is rejected
+    pub fn stop(&self) {
+        self.rejections.all.store(true, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Sonic for &FakeSonic {
+    async fn swap_exact_tokens_for_tokens(
+        &self,
+        amount_in: Nat,
+        amount_out_min: Nat,
+        path: Vec<String>,
+        to: Principal,
+        _deadline: Int,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("swapExactTokensForTokens")?;
+        let mut book = self.book.lock().unwrap();
+
+        let reserves = match book.path_reserves(&path) {
+            Some(reserves) => reserves,
+            None => return Ok((MotokoResult::err(String::from("pair not exists")),)),
+        };
+
+        let mut amounts = vec![amount_in];
+        for (reserve_in, reserve_out) in &reserves {
+            match quote::get_amount_out(amounts.last().unwrap(), reserve_in, reserve_out) {
+                Some(amount_out) => amounts.push(amount_out),
+                None => return Ok((MotokoResult::err(String::from("insufficient liquidity")),)),
+            }
+        }
+
+        if *amounts.last().unwrap() < amount_out_min {
+            return Ok((MotokoResult::err(String::from("slippage exceeded")),));
+        }
+
+        Ok((book.settle_swap(&path, &amounts, self.caller, to),))
+    }
+
+    async fn swap_tokens_for_exact_tokens(
+        &self,
+        amount_out: Nat,
+        amount_in_max: Nat,
+        path: Vec<String>,
+        to: Principal,
+        _deadline: Int,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("swapTokensForExactTokens")?;
+        let mut book = self.book.lock().unwrap();
+
+        let reserves = match book.path_reserves(&path) {
+            Some(reserves) => reserves,
+            None => return Ok((MotokoResult::err(String::from("pair not exists")),)),
+        };
+
+        let mut amounts = vec![amount_out];
+        for (reserve_in, reserve_out) in reserves.iter().rev() {
+            match quote::get_amount_in(amounts.last().unwrap(), reserve_in, reserve_out) {
+                Some(amount_in) => amounts.push(amount_in),
+                None => return Ok((MotokoResult::err(String::from("insufficient liquidity")),)),
+            }
+        }
+        amounts.reverse();
+
+        if amounts[0] > amount_in_max {
+            return Ok((MotokoResult::err(String::from("slippage exceeded")),));
+        }
+
+        Ok((book.settle_swap(&path, &amounts, self.caller, to),))
+    }
+
+    async fn get_pair(
+        &self,
+        token0: Principal,
+        token1: Principal,
+    ) -> CallResult<(Option<SonicPairInfo>,)> {
+        self.rejections.check("getPair")?;
+        let mut book = self.book.lock().unwrap();
+
+        Ok((book
+            .pair_mut(&token0.to_text(), &token1.to_text())
+            .map(|it| it.clone()),))
+    }
+
+    async fn get_all_pairs(&self) -> CallResult<(Vec<SonicPairInfo>,)> {
+        self.rejections.check("getAllPairs")?;
+
+        Ok((self.book.lock().unwrap().pairs.clone(),))
+    }
+
+    async fn get_num_pairs(&self) -> CallResult<(Nat,)> {
+        unsupported("getNumPairs")
+    }
+
+    /// Uniswap V2 `addLiquidity`: one of the desired amounts is cut to the pool price
+    async fn add_liquidity(
+        &self,
+        token0: Principal,
+        token1: Principal,
+        amount0_desired: Nat,
+        amount1_desired: Nat,
+        amount0_min: Nat,
+        amount1_min: Nat,
+        _deadline: Int,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("addLiquidity")?;
+        let mut book = self.book.lock().unwrap();
+
+        let pair = match book.pair_mut(&token0.to_text(), &token1.to_text()) {
+            Some(pair) => pair.clone(),
+            None => return Ok((MotokoResult::err(String::from("pair not exists")),)),
+        };
+        let (reserve0, reserve1) = if pair.token0 == token0.to_text() {
+            (pair.reserve0.clone(), pair.reserve1.clone())
+        } else {
+            (pair.reserve1.clone(), pair.reserve0.clone())
+        };
+
+        let amount1_optimal = amount0_desired.clone() * reserve1.clone() / reserve0.clone();
+        let (amount0, amount1) = if amount1_optimal <= amount1_desired {
+            (amount0_desired, amount1_optimal)
+        } else {
+            let amount0_optimal = amount1_desired.clone() * reserve0.clone() / reserve1.clone();
+            (amount0_optimal, amount1_desired)
+        };
+
+        if amount0 < amount0_min || amount1 < amount1_min {
+            return Ok((MotokoResult::err(String::from("slippage exceeded")),));
+        }
+
+        if book.balance(token0, self.caller) < amount0
+            || book.balance(token1, self.caller) < amount1
+        {
+            return Ok((MotokoResult::err(String::from("insufficient balance")),));
+        }
+        book.debit(token0, self.caller, &amount0);
+        book.debit(token1, self.caller, &amount1);
+
+        let lp_minted = (amount0.clone() * pair.totalSupply.clone() / reserve0)
+            .min(amount1.clone() * pair.totalSupply.clone() / reserve1);
+
+        let pair_mut = book.pair_mut(&pair.token0, &pair.token1).unwrap();
+        if pair_mut.token0 == token0.to_text() {
+            pair_mut.reserve0 += amount0;
+            pair_mut.reserve1 += amount1;
+        } else {
+            pair_mut.reserve0 += amount1;
+            pair_mut.reserve1 += amount0;
+        }
+        pair_mut.totalSupply += lp_minted.clone();
+
+        *book.lp_balances.entry((pair.id, self.caller)).or_default() += lp_minted;
+
+        Ok((book.next_tx(),))
+    }
+
+    async fn remove_liquidity(
+        &self,
+        token0: Principal,
+        token1: Principal,
+        lp_amount: Nat,
+        amount0_min: Nat,
+        amount1_min: Nat,
+        _deadline: Int,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("removeLiquidity")?;
+        let mut book = self.book.lock().unwrap();
+
+        let pair = match book.pair_mut(&token0.to_text(), &token1.to_text()) {
+            Some(pair) => pair.clone(),
+            None => return Ok((MotokoResult::err(String::from("pair not exists")),)),
+        };
+
+        let lp_key = (pair.id.clone(), self.caller);
+        let lp_balance = book.lp_balances.get(&lp_key).cloned().unwrap_or_default();
+        if lp_amount > lp_balance {
+            return Ok((MotokoResult::err(String::from("insufficient LP balance")),));
+        }
+
+        let (reserve0, reserve1) = if pair.token0 == token0.to_text() {
+            (pair.reserve0.clone(), pair.reserve1.clone())
+        } else {
+            (pair.reserve1.clone(), pair.reserve0.clone())
+        };
+        let amount0 = lp_amount.clone() * reserve0 / pair.totalSupply.clone();
+        let amount1 = lp_amount.clone() * reserve1 / pair.totalSupply.clone();
+
+        if amount0 < amount0_min || amount1 < amount1_min {
+            return Ok((MotokoResult::err(String::from("slippage exceeded")),));
+        }
+
+        let pair_mut = book.pair_mut(&pair.token0, &pair.token1).unwrap();
+        if pair_mut.token0 == token0.to_text() {
+            pair_mut.reserve0 = pair_mut.reserve0.clone() - amount0.clone();
+            pair_mut.reserve1 = pair_mut.reserve1.clone() - amount1.clone();
+        } else {
+            pair_mut.reserve0 = pair_mut.reserve0.clone() - amount1.clone();
+            pair_mut.reserve1 = pair_mut.reserve1.clone() - amount0.clone();
+        }
+        pair_mut.totalSupply = pair_mut.totalSupply.clone() - lp_amount.clone();
+
+        book.lp_balances.insert(lp_key, lp_balance - lp_amount);
+        *book.balances.entry((token0, self.caller)).or_default() += amount0;
+        *book.balances.entry((token1, self.caller)).or_default() += amount1;
+
+        Ok((book.next_tx(),))
+    }
+
+    async fn get_user_LP_balances(&self, user: Principal) -> CallResult<(Vec<(String, Nat)>,)> {
+        self.rejections.check("getUserLPBalances")?;
+
+        Ok((self.book.lock().unwrap().lp_balances(user),))
+    }
+
+    async fn add_token(&self, _token_id: Principal) -> CallResult<(SonicTxReceipt,)> {
+        unsupported("addToken")
+    }
+
+    async fn create_pair(
+        &self,
+        _token0: Principal,
+        _token1: Principal,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        unsupported("createPair")
+    }
+
+    async fn deposit(&self, token_id: Principal, value: Nat) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("deposit")?;
+
+        let ledger = match self.ledgers.get(&token_id) {
+            Some(ledger) => ledger,
+            None => return Ok((MotokoResult::err(String::from("token not supported")),)),
+        };
+
+        let transferred = ledger.transfer_from(
+            self.canister_id,
+            self.caller,
+            self.canister_id,
+            value.clone(),
+        );
+        if let Err(e) = transferred {
+            return Ok((MotokoResult::err(format!("transferFrom failed: {:?}", e)),));
+        }
+
+        let mut book = self.book.lock().unwrap();
+        *book.balances.entry((token_id, self.caller)).or_default() += value;
+
+        Ok((book.next_tx(),))
+    }
+
+    async fn withdraw(&self, token_id: Principal, value: Nat) -> CallResult<(SonicTxReceipt,)> {
+        self.rejections.check("withdraw")?;
+
+        let ledger = match self.ledgers.get(&token_id) {
+            Some(ledger) => ledger,
+            None => return Ok((MotokoResult::err(String::from("token not supported")),)),
+        };
+
+        let mut book = self.book.lock().unwrap();
+        if !book.debit(token_id, self.caller, &value) {
+            return Ok((MotokoResult::err(String::from("insufficient balance")),));
+        }
+
+        if let Err(e) = ledger.transfer(self.canister_id, self.caller, value) {
+            return Ok((MotokoResult::err(format!("transfer failed: {:?}", e)),));
+        }
+
+        Ok((book.next_tx(),))
+    }
+
+    async fn transfer(
+        &self,
+        _token_id: String,
+        _to: Principal,
+        _value: Nat,
+    ) -> CallResult<(bool,)> {
+        unsupported("transfer")
+    }
+
+    async fn approve(
+        &self,
+        _token_id: String,
+        _spender: Principal,
+        _value: Nat,
+    ) -> CallResult<(bool,)> {
+        unsupported("approve")
+    }
+
+    async fn transfer_from(
+        &self,
+        _token_id: String,
+        _from: Principal,
+        _to: Principal,
+        _value: Nat,
+    ) -> CallResult<(SonicTxReceipt,)> {
+        unsupported("transferFrom")
+    }
+
+    async fn get_supported_token_list(&self) -> CallResult<(SonicTokenInfo,)> {
+        unsupported("getSupportedTokenList")
+    }
+
+    async fn balance_of(&self, token_id: String, who: Principal) -> CallResult<(Nat,)> {
+        self.rejections.check("balanceOf")?;
+        let token = Principal::from_text(token_id)
+            .map_err(|e| (RejectionCode::CanisterError, e.to_string()))?;
+
+        Ok((self.balance(token, who),))
+    }
+
+    async fn allowance(
+        &self,
+        _token_id: String,
+        _owner: Principal,
+        _spender: Principal,
+    ) -> CallResult<(Nat,)> {
+        unsupported("allowance")
+    }
+
+    async fn total_supply(&self, _token_id: String) -> CallResult<(Nat,)> {
+        unsupported("totalSupply")
+    }
+
+    async fn name(&self, _token_id: String) -> CallResult<(String,)> {
+        unsupported("name")
+    }
+
+    async fn decimals(&self, _token_id: String) -> CallResult<(Nat,)> {
+        unsupported("decimals")
+    }
+
+    async fn symbol(&self, _token_id: String) -> CallResult<(String,)> {
+        unsupported("symbol")
+    }
+
+    async fn get_user_info(&self, user: Principal) -> CallResult<(SonicUserInfo,)> {
+        self.rejections.check("getUserInfo")?;
+        let book = self.book.lock().unwrap();
+
+        Ok((SonicUserInfo {
+            balances: book
+                .balances
+                .iter()
+                .filter(|((_, who), _)| *who == user)
+                .map(|((token, _), balance)| (*token, balance.clone()))
+                .collect(),
+            lpBalances: book.lp_balances(user),
+        },))
+    }
+
+    async fn get_swap_info(&self) -> CallResult<(SonicSwapInfo,)> {
+        self.rejections.check("getSwapInfo")?;
+
+        let mut tokens: Vec<_> = self
+            .ledgers
+            .values()
+            .map(|ledger| SonicTokenInfo {
+                id: ledger.canister_id.to_text(),
+                name: ledger.symbol.clone(),
+                symbol: ledger.symbol.clone(),
+                decimals: ledger.decimals,
+                fee: Nat::from(0),
+                totalSupply: ledger.total_supply(),
+            })
+            .collect();
+        tokens.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok((SonicSwapInfo {
+            owner: Principal::anonymous(),
+            cycles: Nat::from(0),
+            tokens,
+            pairs: self.book.lock().unwrap().pairs.clone(),
+        },))
+    }
+}
+
+/// The XTC canister, minting and burning its tokens one for one with cycles
+pub struct FakeXtc {
+    caller: Principal,
+    ledger: Arc<FakeLedger>,
+}
+
+impl FakeXtc {
+    pub fn new(caller: Principal, ledger: Arc<FakeLedger>) -> Self {
+        FakeXtc { caller, ledger }
+    }
+}
+
+#[async_trait]
+impl XTC for &FakeXtc {
+    async fn mint(&self, to: Principal, cycles: u64) -> CallResult<(XTCMintResult,)> {
+        self.ledger.rejections.check("mint")?;
+        self.ledger.mint_to(to, Nat::from(cycles));
+
+        Ok((Ok(Nat::from(cycles)),))
+    }
+
+    async fn burn(&self, payload: XTCBurnPayload) -> CallResult<(XTCBurnResult,)> {
+        self.ledger.rejections.check("burn")?;
+        let burned = self.ledger.burn(self.caller, Nat::from(payload.amount));
+
+        Ok((burned.map_err(|_| XTCBurnError::InsufficientBalance),))
+    }
+}
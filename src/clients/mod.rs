@@ -1,3 +1,5 @@
 pub mod dip20;
+#[cfg(test)]
+pub mod fake;
 pub mod sonic;
 pub mod xtc;
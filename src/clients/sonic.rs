@@ -13,7 +13,7 @@ pub struct SonicTokenInfo {
     pub totalSupply: Nat,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct SonicPairInfo {
     pub id: String,
     pub token0: String,
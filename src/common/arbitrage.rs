@@ -0,0 +1,136 @@
+use crate::common::history::MAX_PAGE_SIZE;
+use crate::common::quote::{FEE_DENOMINATOR, FEE_NUMERATOR};
+use crate::common::types::{ArbitrageConfig, ArbitrageLogPage, ArbitrageOpportunity};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+
+// opportunities tend to repeat on every scan until someone takes them, so old ones are dropped
+pub const MAX_LOG_SIZE: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Arbitrage {
+    pub config: ArbitrageConfig,
+    pub last_scan_at: u64,
+    pub log: Vec<ArbitrageOpportunity>,
+    pub next_id: u64,
+}
+
+impl Arbitrage {
+    /// Whether it's time for another scan, marks the scan as started if so
+    pub fn take_due(&mut self, now: u64) -> bool {
+        if !self.config.enabled
+            || self.last_scan_at + 1_000_000_000 * self.config.scan_interval_secs > now
+        {
+            return false;
+        }
+
+        self.last_scan_at = now;
+
+        true
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn log(&mut self, opportunity: ArbitrageOpportunity) {
+        if self.log.len() >= MAX_LOG_SIZE {
+            self.log.remove(0);
+        }
+
+        self.log.push(opportunity);
+    }
+
+    pub fn update(&mut self, opportunity: ArbitrageOpportunity) {
+        if let Some(it) = self.log.iter_mut().find(|it| it.id == opportunity.id) {
+            *it = opportunity;
+        }
+    }
+
+    /// Newest first
+    pub fn page(&self, offset: u64, limit: u64) -> ArbitrageLogPage {
+        let entries = self
+            .log
+            .iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .cloned()
+            .collect();
+
+        ArbitrageLogPage {
+            entries,
+            total: self.log.len() as u64,
+        }
+    }
+}
+
+/// The input that maximises output minus input of a swap along a path ending in the token it
+/// starts with, `None` if no input is profitable.
+///
+/// A single hop maps `x` to `a * x / (b + c * x)`, and so does any chain of hops, so the
+/// optimum has a closed form: `(sqrt(A * B) - B) / C` for the chain's coefficients.
+pub fn optimal_amount_in(reserves: &[(Nat, Nat)]) -> Option<Nat> {
+    let fee_kept = FEE_DENOMINATOR - FEE_NUMERATOR;
+
+    let (mut a, mut b, mut c) = (Nat::from(1), Nat::from(1), Nat::from(0));
+    for (reserve_in, reserve_out) in reserves {
+        let hop_a = reserve_out.clone() * fee_kept;
+        let hop_b = reserve_in.clone() * FEE_DENOMINATOR;
+
+        c = hop_b.clone() * c + a.clone() * fee_kept;
+        a = hop_a * a;
+        b = hop_b * b;
+    }
+
+    if a <= b || c.0 == 0u64.into() {
+        return None;
+    }
+
+    let root = Nat((a * b.clone()).0.sqrt());
+    if root <= b {
+        return None;
+    }
+
+    Some((root - b) / c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::quote;
+    use crate::common::types::OrderDirective;
+    use bigdecimal::ToPrimitive;
+
+    fn profit(reserves: &[(Nat, Nat)], amount_in: Nat) -> i128 {
+        let quote =
+            quote::quote_path(&OrderDirective::GiveExact(amount_in.clone()), reserves).unwrap();
+
+        quote.take_amount.0.to_i128().unwrap() - amount_in.0.to_i128().unwrap()
+    }
+
+    #[test]
+    fn balanced_cycle_has_no_opportunity() {
+        let reserve = Nat::from(1_000_000_000u64);
+        let reserves = vec![(reserve.clone(), reserve.clone()); 3];
+
+        assert_eq!(optimal_amount_in(&reserves), None);
+    }
+
+    #[test]
+    fn optimal_amount_beats_its_neighbours() {
+        // the last pool pays 10% more than the other two charge
+        let reserves = vec![
+            (Nat::from(1_000_000_000u64), Nat::from(1_000_000_000u64)),
+            (Nat::from(1_000_000_000u64), Nat::from(1_000_000_000u64)),
+            (Nat::from(1_000_000_000u64), Nat::from(1_100_000_000u64)),
+        ];
+
+        let amount_in = optimal_amount_in(&reserves).unwrap();
+        let best = profit(&reserves, amount_in.clone());
+
+        assert!(best > 0);
+        assert!(best >= profit(&reserves, amount_in.clone() * 9u64 / 10u64));
+        assert!(best >= profit(&reserves, amount_in * 11u64 / 10u64));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::clients::dip20::Dip20;
+use crate::clients::sonic::Sonic;
+use crate::clients::xtc::XTC;
+use crate::get_state;
+use ic_cdk::export::candid::Principal;
+
+/// Everything the bot talks to outside of its own state: the canisters it calls, its own id, the
+/// clock and the log. Tests replace it with in-memory fakes.
+pub trait Env {
+    fn sonic_at(&self, canister_id: Principal) -> Box<dyn Sonic + '_>;
+    fn token(&self, canister_id: Principal) -> Box<dyn Dip20 + '_>;
+    fn xtc_at(&self, canister_id: Principal) -> Box<dyn XTC + '_>;
+    fn id(&self) -> Principal;
+    fn time(&self) -> u64;
+    fn print(&self, message: &str);
+
+    /// The Sonic canister from the config
+    fn sonic(&self) -> Box<dyn Sonic + '_> {
+        self.sonic_at(get_state().config.sonic_swap_canister)
+    }
+
+    /// The XTC canister from the config
+    fn xtc(&self) -> Box<dyn XTC + '_> {
+        self.xtc_at(get_state().config.xtc_canister)
+    }
+}
+
+/// The real canisters
+pub struct CanisterEnv;
+
+impl Env for CanisterEnv {
+    fn sonic_at(&self, canister_id: Principal) -> Box<dyn Sonic + '_> {
+        Box::new(canister_id)
+    }
+
+    fn token(&self, canister_id: Principal) -> Box<dyn Dip20 + '_> {
+        Box::new(canister_id)
+    }
+
+    fn xtc_at(&self, canister_id: Principal) -> Box<dyn XTC + '_> {
+        Box::new(canister_id)
+    }
+
+    fn id(&self) -> Principal {
+        ic_cdk::id()
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn print(&self, message: &str) {
+        ic_cdk::print(message)
+    }
+}
+
+static mut ENV: Option<&'static dyn Env> = None;
+
+pub fn get_env() -> &'static dyn Env {
+    unsafe { ENV.unwrap_or(&CanisterEnv) }
+}
+
+#[cfg(test)]
+pub fn set_env(env: &'static dyn Env) {
+    unsafe { ENV = Some(env) }
+}
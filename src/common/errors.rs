@@ -0,0 +1,98 @@
+use crate::clients::dip20::Dip20TxError;
+use crate::clients::xtc::{XTCBurnError, XTCMintError};
+use crate::common::types::{Currency, GridId, OcoGroupId, StrategyId};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::export::candid::{self, CandidType, Deserialize, Nat};
+use ic_cron::types::TaskId;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum BotError {
+    CallRejected {
+        method: String,
+        code: String,
+        message: String,
+    },
+    Dip20(Dip20TxError),
+    XTCMint(XTCMintError),
+    XTCBurn(XTCBurnError),
+    Sonic(String),
+    SlippageExceeded(String),
+    InsufficientBalance {
+        currency: Currency,
+        required: Nat,
+        available: Nat,
+    },
+    InsufficientLiquidity,
+    PriceImpactTooHigh {
+        price_impact_bps: u64,
+        max_price_impact_bps: u64,
+    },
+    InvalidArgument(String),
+    TokenNotRegistered(Currency),
+    PairNotFound {
+        give_currency: Currency,
+        take_currency: Currency,
+    },
+    OrderNotFound(TaskId),
+    OcoGroupNotFound(OcoGroupId),
+    GridNotFound(GridId),
+    StrategyNotFound(StrategyId),
+    SchedulingFailed(String),
+}
+
+pub type BotResult<T> = Result<T, BotError>;
+
+impl BotError {
+    /// To be used as `.map_err(BotError::call_rejected("methodName"))` on a `CallResult`
+    pub fn call_rejected(method: &'static str) -> impl FnOnce((RejectionCode, String)) -> Self {
+        move |(code, message)| BotError::CallRejected {
+            method: String::from(method),
+            code: format!("{:?}", code),
+            message,
+        }
+    }
+
+    /// Sonic reports every failure as plain text, slippage violations included
+    pub fn sonic(message: String) -> Self {
+        if message.to_lowercase().contains("slippage") {
+            BotError::SlippageExceeded(message)
+        } else {
+            BotError::Sonic(message)
+        }
+    }
+
+    /// Whether the same swap could go through later, once Sonic answers again or the pool moves
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BotError::CallRejected { .. }
+                | BotError::Sonic(_)
+                | BotError::SlippageExceeded(_)
+                | BotError::InsufficientLiquidity
+                | BotError::PriceImpactTooHigh { .. }
+        )
+    }
+
+    /// To be used as `.map_err(BotError::scheduling)` on the result of putting a task into the queue
+    pub fn scheduling(err: candid::Error) -> Self {
+        BotError::SchedulingFailed(err.to_string())
+    }
+}
+
+impl From<Dip20TxError> for BotError {
+    fn from(err: Dip20TxError) -> Self {
+        BotError::Dip20(err)
+    }
+}
+
+impl From<XTCMintError> for BotError {
+    fn from(err: XTCMintError) -> Self {
+        BotError::XTCMint(err)
+    }
+}
+
+impl From<XTCBurnError> for BotError {
+    fn from(err: XTCBurnError) -> Self {
+        BotError::XTCBurn(err)
+    }
+}
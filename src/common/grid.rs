@@ -0,0 +1,273 @@
+use crate::common::types::{
+    Grid, GridAction, GridConfig, GridId, GridLevel, GridPosition, GridStatus,
+};
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat};
+use std::collections::{HashMap, HashSet};
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Grids {
+    pub grids: HashMap<GridId, Grid>,
+    pub next_id: GridId,
+    /// Levels with a buy in flight
+    pub buying: HashSet<(GridId, u64)>,
+}
+
+impl Grids {
+    pub fn create(&mut self, config: GridConfig, now: u64) -> GridId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let step = (config.upper_price - config.lower_price) / (config.levels - 1) as f64;
+        let levels = (0..config.levels)
+            .map(|i| GridLevel {
+                price: config.lower_price + step * i as f64,
+                position: None,
+            })
+            .collect();
+
+        self.grids.insert(
+            id,
+            Grid {
+                id,
+                config,
+                status: GridStatus::Running,
+                levels,
+                last_price: None,
+                last_checked_at: 0,
+                realised_profit: Int::from(0),
+                round_trips: 0,
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn get(&self, id: GridId) -> Option<&Grid> {
+        self.grids.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: GridId) -> Option<&mut Grid> {
+        self.grids.get_mut(&id)
+    }
+
+    /// Sorted by id
+    pub fn list(&self) -> Vec<Grid> {
+        let mut grids: Vec<_> = self.grids.values().cloned().collect();
+        grids.sort_by_key(|it| it.id);
+
+        grids
+    }
+
+    /// Running grids that weren't checked for at least `interval_nano`, marked as checked now
+    pub fn take_due(&mut self, now: u64, interval_nano: u64) -> Vec<GridId> {
+        let mut due = Vec::new();
+
+        for grid in self.grids.values_mut() {
+            if matches!(grid.status, GridStatus::Running)
+                && grid.last_checked_at + interval_nano <= now
+            {
+                grid.last_checked_at = now;
+                due.push(grid.id);
+            }
+        }
+
+        due.sort_unstable();
+        due
+    }
+
+    /// Remembers the new price and returns the trades caused by the levels it has crossed since
+    /// the previous check. Levels holding a position or being bought are never bought again and
+    /// empty ones are never sold.
+    pub fn take_actions(&mut self, id: GridId, price: f64) -> Vec<GridAction> {
+        let grid = match self.grids.get_mut(&id) {
+            Some(grid) if matches!(grid.status, GridStatus::Running) => grid,
+            _ => return Vec::new(),
+        };
+
+        let last_price = match grid.last_price.replace(price) {
+            Some(last_price) => last_price,
+            None => return Vec::new(),
+        };
+
+        let mut actions = Vec::new();
+
+        if price < last_price {
+            for (index, level) in grid.levels.iter().enumerate().rev() {
+                if price <= level.price
+                    && level.price < last_price
+                    && level.position.is_none()
+                    && !self.buying.contains(&(id, index as u64))
+                {
+                    actions.push(GridAction::Buy(index as u64));
+                }
+            }
+        } else {
+            // a position is sold once the price reaches the level above the one it was bought at
+            for (index, level) in grid.levels.iter().enumerate().skip(1) {
+                if last_price < level.price
+                    && level.price <= price
+                    && grid.levels[index - 1].position.is_some()
+                {
+                    actions.push(GridAction::Sell(index as u64 - 1));
+                }
+            }
+        }
+
+        actions
+    }
+
+    /// Marks the level as being bought before anything is awaited, so a check running in the
+    /// meantime can't buy it again. Returns false if the level holds a position or is already
+    /// being bought.
+    pub fn start_buy(&mut self, id: GridId, level: u64) -> bool {
+        let empty = matches!(
+            self.grids.get(&id),
+            Some(grid) if grid.levels[level as usize].position.is_none()
+        );
+
+        empty && self.buying.insert((id, level))
+    }
+
+    /// The buy didn't go through, the level can be bought again
+    pub fn cancel_buy(&mut self, id: GridId, level: u64) {
+        self.buying.remove(&(id, level));
+    }
+
+    pub fn record_buy(
+        &mut self,
+        id: GridId,
+        level: u64,
+        base_amount: Nat,
+        quote_spent: Nat,
+        now: u64,
+    ) {
+        self.buying.remove(&(id, level));
+
+        if let Some(grid) = self.grids.get_mut(&id) {
+            grid.levels[level as usize].position = Some(GridPosition {
+                base_amount,
+                quote_spent,
+                bought_at: now,
+            });
+        }
+    }
+
+    /// Takes the position out of the level before it is sold, so it can't be sold twice
+    pub fn take_position(&mut self, id: GridId, level: u64) -> Option<GridPosition> {
+        self.grids.get_mut(&id)?.levels[level as usize]
+            .position
+            .take()
+    }
+
+    /// Puts back a position that couldn't be sold
+    pub fn restore_position(&mut self, id: GridId, level: u64, position: GridPosition) {
+        if let Some(grid) = self.grids.get_mut(&id) {
+            grid.levels[level as usize].position = Some(position);
+        }
+    }
+
+    pub fn record_sell(&mut self, id: GridId, position: GridPosition, quote_received: Nat) {
+        if let Some(grid) = self.grids.get_mut(&id) {
+            grid.realised_profit += Int::from(quote_received) - Int::from(position.quote_spent);
+            grid.round_trips += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels at 1, 2, 3 and 4, the price starting at 3.5
+    fn running_grid() -> (Grids, GridId) {
+        let mut grids = Grids::default();
+        let id = grids.create(
+            GridConfig {
+                base_currency: String::from("XTC"),
+                quote_currency: String::from("WICP"),
+                lower_price: 1.0,
+                upper_price: 4.0,
+                levels: 4,
+                level_size: Nat::from(100u64),
+            },
+            0,
+        );
+        assert!(grids.take_actions(id, 3.5).is_empty());
+
+        (grids, id)
+    }
+
+    fn buy(grids: &mut Grids, id: GridId, level: u64) {
+        assert!(grids.start_buy(id, level));
+        grids.record_buy(id, level, Nat::from(100u64), Nat::from(100u64), 0);
+    }
+
+    fn levels(actions: &[GridAction]) -> Vec<u64> {
+        actions
+            .iter()
+            .map(|it| match it {
+                GridAction::Buy(level) | GridAction::Sell(level) => *level,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_crossed_level_is_bought_from_the_top() {
+        let (mut grids, id) = running_grid();
+
+        let actions = grids.take_actions(id, 1.5);
+
+        assert!(actions.iter().all(|it| matches!(it, GridAction::Buy(_))));
+        assert_eq!(levels(&actions), vec![2, 1]);
+    }
+
+    #[test]
+    fn levels_holding_a_position_are_not_bought_again() {
+        let (mut grids, id) = running_grid();
+        buy(&mut grids, id, 2);
+
+        let actions = grids.take_actions(id, 1.5);
+
+        assert_eq!(levels(&actions), vec![1]);
+        assert!(!grids.start_buy(id, 2));
+    }
+
+    #[test]
+    fn levels_being_bought_are_not_bought_again() {
+        let (mut grids, id) = running_grid();
+        let actions = grids.take_actions(id, 1.5);
+        for level in levels(&actions) {
+            assert!(grids.start_buy(id, level));
+        }
+
+        // the price bounces while the buys are still in flight
+        assert!(grids.take_actions(id, 3.5).is_empty());
+        assert!(grids.take_actions(id, 1.5).is_empty());
+        assert!(!grids.start_buy(id, 1));
+
+        grids.cancel_buy(id, 1);
+        grids.take_actions(id, 3.5);
+        assert_eq!(levels(&grids.take_actions(id, 1.5)), vec![1]);
+    }
+
+    #[test]
+    fn position_is_sold_at_the_level_above() {
+        let (mut grids, id) = running_grid();
+        grids.take_actions(id, 1.5);
+        buy(&mut grids, id, 1);
+
+        assert!(grids.take_actions(id, 2.5).is_empty());
+        let actions = grids.take_actions(id, 3.5);
+
+        assert!(matches!(actions[..], [GridAction::Sell(1)]));
+    }
+
+    #[test]
+    fn paused_grid_does_nothing() {
+        let (mut grids, id) = running_grid();
+        grids.get_mut(id).unwrap().status = GridStatus::Paused;
+
+        assert!(grids.take_actions(id, 1.5).is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use crate::get_state;
 use ic_cdk::caller;
 
 pub fn controller_guard() -> Result<(), String> {
-    if caller() != get_state().controller {
+    if caller() != get_state().config.controller {
         return Err(String::from("Access denied"));
     }
 
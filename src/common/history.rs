@@ -0,0 +1,193 @@
+use crate::common::types::{Currency, TradeFilter, TradeHistoryPage, TradeRecord};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+// so a single page always fits into a message
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+// the whole history is copied through stable memory on every upgrade, so the oldest swaps are
+// dropped once there are this many
+pub const MAX_HISTORY_SIZE: usize = 10_000;
+
+/// The latest swaps the bot has executed, oldest first
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct TradeHistory {
+    pub records: Vec<TradeRecord>,
+    pub next_id: u64,
+}
+
+impl TradeHistory {
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn push(&mut self, record: TradeRecord) {
+        if self.records.len() >= MAX_HISTORY_SIZE {
+            self.records.remove(0);
+        }
+
+        self.records.push(record);
+    }
+
+    /// Matching records, newest first. The total only counts the records still kept.
+    pub fn page(&self, offset: u64, limit: u64, filter: &TradeFilter) -> TradeHistoryPage {
+        let matching: Vec<_> = self
+            .records
+            .iter()
+            .rev()
+            .filter(|it| filter.matches(it))
+            .collect();
+
+        let records = matching
+            .iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|it| (*it).clone())
+            .collect();
+
+        TradeHistoryPage {
+            records,
+            total: matching.len() as u64,
+        }
+    }
+}
+
+impl TradeFilter {
+    pub fn matches(&self, record: &TradeRecord) -> bool {
+        if let Some(currency) = &self.currency {
+            if !involves(record, currency) {
+                return false;
+            }
+        }
+
+        if let Some(task_id) = self.task_id {
+            if record.origin.task_id() != Some(task_id) {
+                return false;
+            }
+        }
+
+        if let Some(from) = self.from_timestamp {
+            if record.timestamp < from {
+                return false;
+            }
+        }
+
+        if let Some(to) = self.to_timestamp {
+            if record.timestamp > to {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn involves(record: &TradeRecord, currency: &Currency) -> bool {
+    record.give_currency == *currency || record.take_currency == *currency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{Mode, TradeOrigin};
+    use ic_cdk::export::candid::Nat;
+
+    /// A swap of the first currency for the second one every second, every other one by a limit
+    /// order
+    fn swaps(count: u64, currencies: (&str, &str)) -> TradeHistory {
+        let mut history = TradeHistory::default();
+        for i in 0..count {
+            let record = TradeRecord {
+                id: history.next_id(),
+                origin: if i % 2 == 0 {
+                    TradeOrigin::Limit(7)
+                } else {
+                    TradeOrigin::Market
+                },
+                give_currency: String::from(currencies.0),
+                take_currency: String::from(currencies.1),
+                give_amount: Nat::from(100u64),
+                take_amount: Nat::from(99u64),
+                path: vec![String::from(currencies.0), String::from(currencies.1)],
+                quoted_price: 1f64,
+                realised_price: 1f64,
+                timestamp: i,
+                sonic_receipt: Nat::from(0u64),
+                mode: Mode::Live,
+            };
+            history.push(record);
+        }
+
+        history
+    }
+
+    fn ids(page: &TradeHistoryPage) -> Vec<u64> {
+        page.records.iter().map(|it| it.id).collect()
+    }
+
+    #[test]
+    fn pages_go_from_newest_to_oldest() {
+        let history = swaps(5, ("XTC", "WICP"));
+        let filter = TradeFilter::default();
+
+        assert_eq!(ids(&history.page(0, 2, &filter)), vec![4, 3]);
+        assert_eq!(ids(&history.page(2, 2, &filter)), vec![2, 1]);
+        assert_eq!(ids(&history.page(4, 2, &filter)), vec![0]);
+        assert!(history.page(5, 2, &filter).records.is_empty());
+        assert_eq!(history.page(4, 2, &filter).total, 5);
+    }
+
+    #[test]
+    fn page_size_is_capped() {
+        let history = swaps(MAX_PAGE_SIZE + 10, ("XTC", "WICP"));
+
+        let page = history.page(0, u64::MAX, &TradeFilter::default());
+
+        assert_eq!(page.records.len() as u64, MAX_PAGE_SIZE);
+        assert_eq!(page.total, MAX_PAGE_SIZE + 10);
+    }
+
+    #[test]
+    fn filters_narrow_down_the_records() {
+        let mut history = swaps(6, ("XTC", "WICP"));
+        let other = swaps(2, ("WICP", "GHOST"));
+        for mut record in other.records {
+            record.id = history.next_id();
+            history.push(record);
+        }
+
+        let by_currency = TradeFilter {
+            currency: Some(String::from("GHOST")),
+            ..TradeFilter::default()
+        };
+        assert_eq!(ids(&history.page(0, 10, &by_currency)), vec![7, 6]);
+
+        let by_task = TradeFilter {
+            task_id: Some(7),
+            ..TradeFilter::default()
+        };
+        assert_eq!(ids(&history.page(0, 10, &by_task)), vec![6, 4, 2, 0]);
+
+        let by_time = TradeFilter {
+            currency: Some(String::from("XTC")),
+            from_timestamp: Some(2),
+            to_timestamp: Some(4),
+            ..TradeFilter::default()
+        };
+        let page = history.page(0, 1, &by_time);
+        assert_eq!(ids(&page), vec![4]);
+        assert_eq!(page.total, 3);
+    }
+
+    #[test]
+    fn oldest_records_are_dropped_once_the_history_is_full() {
+        let history = swaps(MAX_HISTORY_SIZE as u64 + 3, ("XTC", "WICP"));
+
+        assert_eq!(history.records.len(), MAX_HISTORY_SIZE);
+        assert_eq!(history.records[0].id, 3);
+
+        let page = history.page(0, 1, &TradeFilter::default());
+        assert_eq!(ids(&page), vec![MAX_HISTORY_SIZE as u64 + 2]);
+        assert_eq!(page.total, MAX_HISTORY_SIZE as u64);
+    }
+}
@@ -0,0 +1,76 @@
+//! What the first version of the bot kept in stable memory. A canister upgraded from it comes
+//! back with its config and its pending limit orders, everything else starts out empty.
+
+use crate::common::types::{
+    BotConfig, Currency, LimitOrder, MarketOrder, OrderDirective, ScheduledOrder, TargetPrice,
+};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct LegacyState {
+    pub xtc_canister: Principal,
+    pub wicp_canister: Principal,
+    pub sonic_swap_canister: Principal,
+    pub controller: Principal,
+}
+
+impl LegacyState {
+    pub fn config(&self) -> BotConfig {
+        BotConfig {
+            xtc_canister: self.xtc_canister,
+            wicp_canister: self.wicp_canister,
+            sonic_swap_canister: self.sonic_swap_canister,
+            controller: self.controller,
+        }
+    }
+}
+
+/// Only the two well-known tokens could be traded back then. The variants are named the way they
+/// were serialized.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum LegacyCurrency {
+    XTC,
+    WICP,
+}
+
+impl LegacyCurrency {
+    pub fn symbol(&self) -> Currency {
+        match self {
+            LegacyCurrency::XTC => String::from("XTC"),
+            LegacyCurrency::WICP => String::from("WICP"),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LegacyMarketOrder {
+    pub give_currency: LegacyCurrency,
+    pub take_currency: LegacyCurrency,
+    pub directive: OrderDirective,
+}
+
+/// The payload of every task the first version put into the cron queue
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LegacyLimitOrder {
+    pub target_price_condition: TargetPrice,
+    pub market_order: LegacyMarketOrder,
+}
+
+impl LegacyLimitOrder {
+    /// The same order with the default trading limits and no time in force
+    pub fn migrate(self) -> ScheduledOrder {
+        ScheduledOrder::Limit(LimitOrder {
+            target_price_condition: self.target_price_condition,
+            market_order: MarketOrder {
+                give_currency: self.market_order.give_currency.symbol(),
+                take_currency: self.market_order.take_currency.symbol(),
+                directive: self.market_order.directive,
+                max_slippage_bps: None,
+                deadline_secs: None,
+                max_price_impact_bps: None,
+            },
+            time_in_force: None,
+        })
+    }
+}
@@ -0,0 +1,323 @@
+use crate::clients::sonic::SonicPairInfo;
+use crate::common::quote::{to_decimal, BPS};
+use crate::common::types::{Currency, LpEntry, LpRecord, LpReport};
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use std::collections::HashMap;
+
+/// How much of the other token has to be added along with `amount_a` to keep the pool price,
+/// `None` if the pool is empty and any ratio would do
+pub fn paired_amount(amount_a: &Nat, reserve_a: &Nat, reserve_b: &Nat) -> Option<Nat> {
+    if reserve_a.0 == 0u64.into() || reserve_b.0 == 0u64.into() {
+        return None;
+    }
+
+    Some(amount_a.clone() * reserve_b.clone() / reserve_a.clone())
+}
+
+/// Tokens the LP tokens can be redeemed for right now
+pub fn underlying_amounts(
+    lp_amount: &Nat,
+    total_supply: &Nat,
+    reserve0: &Nat,
+    reserve1: &Nat,
+) -> (Nat, Nat) {
+    if total_supply.0 == 0u64.into() {
+        return (Nat::from(0), Nat::from(0));
+    }
+
+    (
+        lp_amount.clone() * reserve0.clone() / total_supply.clone(),
+        lp_amount.clone() * reserve1.clone() / total_supply.clone(),
+    )
+}
+
+/// Value of one LP token measured in `sqrt(k)`, which only grows with the fees the pool
+/// collects. LP tokens Sonic is yet to mint for itself as the protocol fee, once `k` has grown
+/// past `k_last`, are counted as already minted.
+pub fn root_k_per_lp(reserve0: &Nat, reserve1: &Nat, total_supply: &Nat, k_last: &Nat) -> f64 {
+    if total_supply.0 == 0u64.into() {
+        return 0f64;
+    }
+
+    let root_k = Nat((reserve0.clone() * reserve1.clone()).0.sqrt());
+    let root_k_last = Nat(k_last.0.sqrt());
+
+    let mut supply = total_supply.clone();
+    if k_last.0 != 0u64.into() && root_k > root_k_last {
+        supply += total_supply.clone() * (root_k.clone() - root_k_last.clone())
+            / (root_k.clone() * 5u64 + root_k_last);
+    }
+
+    to_f64(&root_k) / to_f64(&supply)
+}
+
+fn to_f64(amount: &Nat) -> f64 {
+    to_decimal(amount).to_f64().unwrap()
+}
+
+/// LP positions opened by the bot, keyed by the pair id
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct LpBook {
+    pub positions: HashMap<String, LpRecord>,
+}
+
+impl LpBook {
+    pub fn record_add(
+        &mut self,
+        pair_id: String,
+        token0: Principal,
+        token1: Principal,
+        entry: LpEntry,
+    ) {
+        let record = self
+            .positions
+            .entry(pair_id.clone())
+            .or_insert_with(|| LpRecord {
+                pair_id,
+                token0,
+                token1,
+                deposited0: Nat::from(0),
+                deposited1: Nat::from(0),
+                lp_amount: Nat::from(0),
+                entry_root_k_per_lp: 0f64,
+                entries: Vec::new(),
+            });
+
+        let lp_before = to_f64(&record.lp_amount);
+        let lp_minted = to_f64(&entry.lp_minted);
+        let entry_root_k_per_lp = root_k_per_lp(
+            &entry.reserve0,
+            &entry.reserve1,
+            &entry.total_supply,
+            &entry.k_last,
+        );
+
+        if lp_before + lp_minted > 0f64 {
+            record.entry_root_k_per_lp = (record.entry_root_k_per_lp * lp_before
+                + entry_root_k_per_lp * lp_minted)
+                / (lp_before + lp_minted);
+        }
+
+        record.deposited0 += entry.amount0.clone();
+        record.deposited1 += entry.amount1.clone();
+        record.lp_amount += entry.lp_minted.clone();
+        record.entries.push(entry);
+    }
+
+    /// The deposits shrink in proportion to the LP tokens redeemed
+    pub fn record_remove(&mut self, pair_id: &str, lp_amount: &Nat) {
+        let record = match self.positions.get_mut(pair_id) {
+            Some(record) => record,
+            None => return,
+        };
+
+        if *lp_amount >= record.lp_amount {
+            self.positions.remove(pair_id);
+            return;
+        }
+
+        let lp_left = record.lp_amount.clone() - lp_amount.clone();
+        record.deposited0 = record.deposited0.clone() * lp_left.clone() / record.lp_amount.clone();
+        record.deposited1 = record.deposited1.clone() * lp_left.clone() / record.lp_amount.clone();
+        record.lp_amount = lp_left;
+    }
+}
+
+/// Compares the position against simply holding what was deposited, both valued in token1 at
+/// the current pool price
+pub fn report(
+    record: &LpRecord,
+    pair: &SonicPairInfo,
+    symbols: &HashMap<String, Currency>,
+) -> LpReport {
+    let (amount0, amount1) = underlying_amounts(
+        &record.lp_amount,
+        &pair.totalSupply,
+        &pair.reserve0,
+        &pair.reserve1,
+    );
+
+    let price = if pair.reserve0.0 == 0u64.into() {
+        0f64
+    } else {
+        to_f64(&pair.reserve1) / to_f64(&pair.reserve0)
+    };
+
+    let hold_value = to_f64(&record.deposited0) * price + to_f64(&record.deposited1);
+    let lp_value = to_f64(&amount0) * price + to_f64(&amount1);
+
+    let value_vs_hold = ratio(lp_value, hold_value);
+    let fee_yield = ratio(
+        root_k_per_lp(
+            &pair.reserve0,
+            &pair.reserve1,
+            &pair.totalSupply,
+            &pair.kLast,
+        ),
+        record.entry_root_k_per_lp,
+    );
+    // LP value = hold value * (1 + impermanent loss) * (1 + fee yield)
+    let impermanent_loss = ratio(1f64 + value_vs_hold, 1f64 + fee_yield);
+
+    LpReport {
+        pair_id: record.pair_id.clone(),
+        currency0: symbols.get(&pair.token0).cloned(),
+        currency1: symbols.get(&pair.token1).cloned(),
+        lp_amount: record.lp_amount.clone(),
+        deposited0: record.deposited0.clone(),
+        deposited1: record.deposited1.clone(),
+        amount0,
+        amount1,
+        hold_value,
+        lp_value,
+        value_vs_hold_bps: to_bps(value_vs_hold),
+        impermanent_loss_bps: to_bps(impermanent_loss),
+        fee_yield_bps: to_bps(fee_yield),
+        entries: record.entries.clone(),
+    }
+}
+
+/// `a / b - 1`, zero if there is nothing to compare against
+fn ratio(a: f64, b: f64) -> f64 {
+    if b > 0f64 {
+        a / b - 1f64
+    } else {
+        0f64
+    }
+}
+
+fn to_bps(ratio: f64) -> i64 {
+    (ratio * BPS as f64).round() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::export::candid::Int;
+
+    fn nat(amount: u64) -> Nat {
+        Nat::from(amount)
+    }
+
+    fn pair(reserve0: u64, reserve1: u64, total_supply: u64) -> SonicPairInfo {
+        SonicPairInfo {
+            id: String::from("a:b"),
+            token0: String::from("a"),
+            token1: String::from("b"),
+            creator: Principal::anonymous(),
+            reserve0: nat(reserve0),
+            reserve1: nat(reserve1),
+            price0CumulativeLast: nat(0),
+            price1CumulativeLast: nat(0),
+            kLast: nat(0),
+            blockTimestampLast: Int::from(0),
+            totalSupply: nat(total_supply),
+            lptoken: String::from("a:b"),
+        }
+    }
+
+    /// 100 of each token added to a pool of 1000 and 1000 with 1000 LP tokens out
+    fn book() -> LpBook {
+        let mut book = LpBook::default();
+        book.record_add(
+            String::from("a:b"),
+            Principal::anonymous(),
+            Principal::anonymous(),
+            LpEntry {
+                timestamp: 0,
+                reserve0: nat(1_000),
+                reserve1: nat(1_000),
+                total_supply: nat(1_000),
+                k_last: nat(0),
+                amount0: nat(100),
+                amount1: nat(100),
+                lp_minted: nat(100),
+            },
+        );
+
+        book
+    }
+
+    #[test]
+    fn paired_amount_keeps_the_pool_price() {
+        assert_eq!(
+            paired_amount(&nat(100), &nat(1_000), &nat(2_000)),
+            Some(nat(200))
+        );
+        assert_eq!(paired_amount(&nat(100), &nat(0), &nat(0)), None);
+    }
+
+    #[test]
+    fn lp_tokens_redeem_their_share_of_the_reserves() {
+        assert_eq!(
+            underlying_amounts(&nat(10), &nat(100), &nat(1_000), &nat(2_000)),
+            (nat(100), nat(200))
+        );
+        assert_eq!(
+            underlying_amounts(&nat(10), &nat(0), &nat(1_000), &nat(2_000)),
+            (nat(0), nat(0))
+        );
+    }
+
+    #[test]
+    fn pending_protocol_fee_dilutes_lp_tokens() {
+        assert_eq!(
+            root_k_per_lp(&nat(100), &nat(400), &nat(100), &nat(0)),
+            2f64
+        );
+        assert_eq!(
+            root_k_per_lp(&nat(100), &nat(400), &nat(100), &nat(40_000)),
+            2f64
+        );
+
+        // sqrt(k) doubled since the fee was last taken, a sixth of the growth goes to Sonic
+        let value = root_k_per_lp(&nat(400), &nat(400), &nat(100), &nat(40_000));
+        assert_eq!(value, 400f64 / 109f64);
+    }
+
+    #[test]
+    fn price_move_shows_up_as_impermanent_loss() {
+        // token0 is four times as expensive as before, with k unchanged
+        let report = report(
+            &book().positions["a:b"],
+            &pair(500, 2_000, 1_000),
+            &HashMap::new(),
+        );
+
+        assert_eq!(report.amount0, nat(50));
+        assert_eq!(report.amount1, nat(200));
+        assert_eq!(report.hold_value, 500f64);
+        assert_eq!(report.lp_value, 400f64);
+        assert_eq!(report.value_vs_hold_bps, -2_000);
+        assert_eq!(report.impermanent_loss_bps, -2_000);
+        assert_eq!(report.fee_yield_bps, 0);
+    }
+
+    #[test]
+    fn fees_show_up_as_yield_without_loss() {
+        let report = report(
+            &book().positions["a:b"],
+            &pair(1_100, 1_100, 1_000),
+            &HashMap::new(),
+        );
+
+        assert_eq!(report.value_vs_hold_bps, 1_000);
+        assert_eq!(report.fee_yield_bps, 1_000);
+        assert_eq!(report.impermanent_loss_bps, 0);
+    }
+
+    #[test]
+    fn removing_liquidity_shrinks_the_deposits() {
+        let mut book = book();
+
+        book.record_remove("a:b", &nat(25));
+        let record = &book.positions["a:b"];
+        assert_eq!(record.lp_amount, nat(75));
+        assert_eq!(record.deposited0, nat(75));
+        assert_eq!(record.deposited1, nat(75));
+
+        book.record_remove("a:b", &nat(75));
+        assert!(book.positions.is_empty());
+    }
+}
@@ -1,2 +1,17 @@
+pub mod arbitrage;
+pub mod env;
+pub mod errors;
+pub mod grid;
 pub mod guards;
+pub mod history;
+pub mod legacy;
+pub mod liquidity;
+pub mod oco;
+pub mod paper;
+pub mod quote;
+pub mod rebalance;
+pub mod registry;
+pub mod reservations;
+pub mod router;
+pub mod strategy;
 pub mod types;
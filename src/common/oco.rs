@@ -0,0 +1,67 @@
+use crate::common::types::{OcoGroup, OcoGroupId, OcoStatus};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cron::types::TaskId;
+use std::collections::HashMap;
+
+/// One-cancels-other groups. Their ids are counted separately from the task ids of their legs.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct OcoGroups {
+    pub groups: HashMap<OcoGroupId, OcoGroup>,
+    pub group_by_leg: HashMap<TaskId, OcoGroupId>,
+    pub next_id: OcoGroupId,
+}
+
+impl OcoGroups {
+    pub fn create(&mut self, legs: Vec<TaskId>) -> OcoGroupId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for leg in &legs {
+            self.group_by_leg.insert(*leg, id);
+        }
+
+        self.groups.insert(
+            id,
+            OcoGroup {
+                id,
+                legs,
+                status: OcoStatus::Active,
+            },
+        );
+
+        id
+    }
+
+    pub fn get(&self, id: OcoGroupId) -> Option<&OcoGroup> {
+        self.groups.get(&id)
+    }
+
+    /// Finds a group by the task id of any of its legs
+    pub fn get_by_leg(&self, task_id: TaskId) -> Option<&OcoGroup> {
+        let id = self.group_by_leg.get(&task_id)?;
+
+        self.groups.get(id)
+    }
+
+    /// Other legs of the group, if the order is a part of an active one
+    pub fn siblings(&self, task_id: TaskId) -> Vec<TaskId> {
+        match self.get_by_leg(task_id) {
+            Some(group) if matches!(group.status, OcoStatus::Active) => group
+                .legs
+                .iter()
+                .filter(|it| **it != task_id)
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Updates the group the order is a leg of, if there is one
+    pub fn set_status(&mut self, task_id: TaskId, status: OcoStatus) {
+        if let Some(id) = self.group_by_leg.get(&task_id) {
+            if let Some(group) = self.groups.get_mut(id) {
+                group.status = status;
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::common::errors::{BotError, BotResult};
+use crate::common::types::Currency;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use std::collections::HashMap;
+
+/// Virtual Sonic balances that trades are settled in while the bot is in paper mode
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PaperAccount {
+    pub balances: HashMap<Currency, Nat>,
+}
+
+impl PaperAccount {
+    pub fn balance(&self, currency: &Currency) -> Nat {
+        self.balances.get(currency).cloned().unwrap_or_default()
+    }
+
+    pub fn set_balance(&mut self, currency: Currency, amount: Nat) {
+        self.balances.insert(currency, amount);
+    }
+
+    /// Takes `give_amount` and adds `take_amount`, the same token may be on both sides
+    pub fn settle_swap(
+        &mut self,
+        give_currency: &Currency,
+        give_amount: &Nat,
+        take_currency: &Currency,
+        take_amount: &Nat,
+    ) -> BotResult<()> {
+        let available = self.balance(give_currency);
+        if *give_amount > available {
+            return Err(BotError::InsufficientBalance {
+                currency: give_currency.clone(),
+                required: give_amount.clone(),
+                available,
+            });
+        }
+
+        self.set_balance(give_currency.clone(), available - give_amount.clone());
+        *self.balances.entry(take_currency.clone()).or_default() += take_amount.clone();
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<(Currency, Nat)> {
+        let mut balances: Vec<_> = self
+            .balances
+            .iter()
+            .map(|(currency, balance)| (currency.clone(), balance.clone()))
+            .collect();
+        balances.sort_by(|a, b| a.0.cmp(&b.0));
+
+        balances
+    }
+}
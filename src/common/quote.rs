@@ -0,0 +1,237 @@
+use crate::clients::sonic::SonicPairInfo;
+use crate::common::types::{OrderDirective, SwapQuote};
+use bigdecimal::num_bigint::{BigUint, ToBigInt};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use ic_cdk::export::candid::{Nat, Principal};
+
+// Sonic charges 0.3% of the input amount, the same way Uniswap V2 does
+pub const FEE_NUMERATOR: u64 = 3;
+pub const FEE_DENOMINATOR: u64 = 1000;
+
+pub const BPS: u64 = 10_000;
+
+/// Sonic keeps the tokens of a pair in canonical order, no matter in which order they were
+/// requested, so reserves have to be matched against the tokens explicitly
+pub fn oriented_reserves(
+    pair: &SonicPairInfo,
+    give_token: Principal,
+    take_token: Principal,
+) -> Option<(Nat, Nat)> {
+    let give_token = give_token.to_text();
+    let take_token = take_token.to_text();
+
+    if pair.token0 == give_token && pair.token1 == take_token {
+        Some((pair.reserve0.clone(), pair.reserve1.clone()))
+    } else if pair.token0 == take_token && pair.token1 == give_token {
+        Some((pair.reserve1.clone(), pair.reserve0.clone()))
+    } else {
+        None
+    }
+}
+
+/// How many give tokens one take token costs, without fee and price impact
+pub fn spot_price(reserve_in: &Nat, reserve_out: &Nat) -> BigDecimal {
+    price(reserve_in, reserve_out)
+}
+
+/// The spot price of a swap through several pools, each given by its input and output reserves
+pub fn path_spot_price(reserves: &[(Nat, Nat)]) -> BigDecimal {
+    let (reserves_in, reserves_out) = reserve_products(reserves);
+
+    price(&reserves_in, &reserves_out)
+}
+
+fn reserve_products(reserves: &[(Nat, Nat)]) -> (Nat, Nat) {
+    reserves.iter().fold(
+        (Nat::from(1), Nat::from(1)),
+        |(product_in, product_out), (reserve_in, reserve_out)| {
+            (
+                product_in * reserve_in.clone(),
+                product_out * reserve_out.clone(),
+            )
+        },
+    )
+}
+
+/// How many give tokens were paid for one take token, zero if nothing was taken
+pub fn price(give_amount: &Nat, take_amount: &Nat) -> BigDecimal {
+    if is_zero(take_amount) {
+        return BigDecimal::from(0);
+    }
+
+    to_decimal(give_amount) / to_decimal(take_amount)
+}
+
+/// Sonic's `getAmountOut` - the output of a swap of exactly `amount_in` tokens
+pub fn get_amount_out(amount_in: &Nat, reserve_in: &Nat, reserve_out: &Nat) -> Option<Nat> {
+    if is_zero(reserve_in) || is_zero(reserve_out) {
+        return None;
+    }
+
+    let amount_in_with_fee = amount_in.clone() * (FEE_DENOMINATOR - FEE_NUMERATOR);
+    let numerator = amount_in_with_fee.clone() * reserve_out.clone();
+    let denominator = reserve_in.clone() * FEE_DENOMINATOR + amount_in_with_fee;
+
+    Some(numerator / denominator)
+}
+
+/// Sonic's `getAmountIn` - the input needed to receive exactly `amount_out` tokens
+pub fn get_amount_in(amount_out: &Nat, reserve_in: &Nat, reserve_out: &Nat) -> Option<Nat> {
+    if is_zero(reserve_in) || is_zero(reserve_out) || amount_out >= reserve_out {
+        return None;
+    }
+
+    let numerator = reserve_in.clone() * amount_out.clone() * FEE_DENOMINATOR;
+    let denominator =
+        (reserve_out.clone() - amount_out.clone()) * (FEE_DENOMINATOR - FEE_NUMERATOR);
+
+    Some(numerator / denominator + 1u64)
+}
+
+/// Quotes a swap through several pools, each given by its input and output reserves. Returns
+/// `None` if any of the pools can't fill it.
+pub fn quote_path(directive: &OrderDirective, reserves: &[(Nat, Nat)]) -> Option<SwapQuote> {
+    if reserves.is_empty() {
+        return None;
+    }
+
+    let (give_amount, take_amount) = match directive {
+        OrderDirective::GiveExact(give_amount) => {
+            let mut take_amount = give_amount.clone();
+            for (reserve_in, reserve_out) in reserves {
+                take_amount = get_amount_out(&take_amount, reserve_in, reserve_out)?;
+            }
+
+            (give_amount.clone(), take_amount)
+        }
+        OrderDirective::TakeExact(take_amount) => {
+            let mut give_amount = take_amount.clone();
+            for (reserve_in, reserve_out) in reserves.iter().rev() {
+                give_amount = get_amount_in(&give_amount, reserve_in, reserve_out)?;
+            }
+
+            (give_amount, take_amount.clone())
+        }
+    };
+
+    // every hop charges its own fee, here they are all expressed in give tokens
+    let hops = reserves.len() as u32;
+    let fee_denominator = Nat(BigUint::from(FEE_DENOMINATOR).pow(hops));
+    let fee_kept = Nat(BigUint::from(FEE_DENOMINATOR - FEE_NUMERATOR).pow(hops));
+    let fee = give_amount.clone() * (fee_denominator.clone() - fee_kept) / fee_denominator;
+
+    let (reserves_in, reserves_out) = reserve_products(reserves);
+    let price_impact_bps = price_impact_bps(
+        &(give_amount.clone() - fee.clone()),
+        &take_amount,
+        &reserves_in,
+        &reserves_out,
+    );
+
+    Some(SwapQuote {
+        give_amount,
+        take_amount,
+        fee,
+        price_impact_bps,
+    })
+}
+
+/// How much worse than the spot price the curve fills a swap, the fee is not included
+fn price_impact_bps(
+    amount_in_after_fee: &Nat,
+    amount_out: &Nat,
+    reserve_in: &Nat,
+    reserve_out: &Nat,
+) -> u64 {
+    // amount_out we would get at the spot price is amount_in * reserve_out / reserve_in
+    let ideal_out_scaled = amount_in_after_fee.clone() * reserve_out.clone();
+    let actual_out_scaled = amount_out.clone() * reserve_in.clone();
+
+    if is_zero(&ideal_out_scaled) || actual_out_scaled >= ideal_out_scaled {
+        return 0;
+    }
+
+    let impact = (ideal_out_scaled.clone() - actual_out_scaled) * BPS / ideal_out_scaled;
+
+    impact.0.to_u64().unwrap_or(u64::MAX)
+}
+
+pub fn to_decimal(amount: &Nat) -> BigDecimal {
+    BigDecimal::from(amount.0.to_bigint().unwrap())
+}
+
+fn is_zero(amount: &Nat) -> bool {
+    amount.0 == 0u64.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nat(amount: u64) -> Nat {
+        Nat::from(amount)
+    }
+
+    #[test]
+    fn amount_out_takes_fee_and_curve_into_account() {
+        // 1000 * 997 * 1e9 / (1e9 * 1000 + 1000 * 997), rounded down
+        let amount_out = get_amount_out(&nat(1_000), &nat(1_000_000_000), &nat(1_000_000_000));
+
+        assert_eq!(amount_out, Some(nat(996)));
+    }
+
+    #[test]
+    fn amount_in_buys_at_least_the_amount_out() {
+        let (reserve_in, reserve_out) = (nat(5_000_000), nat(3_000_000));
+
+        let amount_in = get_amount_in(&nat(10_000), &reserve_in, &reserve_out).unwrap();
+        let amount_out = get_amount_out(&amount_in, &reserve_in, &reserve_out).unwrap();
+
+        assert!(amount_out >= nat(10_000));
+        assert!(
+            get_amount_out(&(amount_in - 1u64), &reserve_in, &reserve_out).unwrap() < 10_000u64
+        );
+    }
+
+    #[test]
+    fn empty_or_drained_pools_are_not_quoted() {
+        assert_eq!(get_amount_out(&nat(1_000), &nat(0), &nat(1_000)), None);
+        assert_eq!(get_amount_in(&nat(1_000), &nat(1_000), &nat(1_000)), None);
+        assert!(quote_path(&OrderDirective::GiveExact(nat(1_000)), &[]).is_none());
+    }
+
+    #[test]
+    fn path_spot_price_multiplies_hops() {
+        let price = path_spot_price(&[(nat(1), nat(2)), (nat(3), nat(4))]);
+
+        assert_eq!(price, BigDecimal::from(3) / BigDecimal::from(8));
+    }
+
+    #[test]
+    fn multi_hop_quote_charges_every_fee() {
+        let reserves = [
+            (nat(1_000_000_000), nat(1_000_000_000)),
+            (nat(1_000_000_000), nat(1_000_000_000)),
+        ];
+
+        let quote = quote_path(&OrderDirective::GiveExact(nat(1_000_000)), &reserves).unwrap();
+
+        // 1 - 0.997^2 of the input
+        assert_eq!(quote.fee, nat(5_991));
+        assert!(quote.take_amount < nat(1_000_000) - quote.fee.clone());
+        assert!(quote.price_impact_bps > 0 && quote.price_impact_bps < 100);
+    }
+
+    #[test]
+    fn take_exact_quote_gives_enough() {
+        let reserves = [(nat(2_000_000), nat(1_000_000))];
+
+        let quote = quote_path(&OrderDirective::TakeExact(nat(1_000)), &reserves).unwrap();
+
+        assert_eq!(quote.take_amount, nat(1_000));
+        assert_eq!(
+            get_amount_in(&nat(1_000), &reserves[0].0, &reserves[0].1),
+            Some(quote.give_amount)
+        );
+    }
+}
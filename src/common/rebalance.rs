@@ -0,0 +1,220 @@
+use crate::common::quote::BPS;
+use crate::common::types::{Holding, MarketOrder, OrderDirective, RebalanceConfig, RebalancePlan};
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use ic_cron::types::TaskId;
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Rebalancer {
+    pub config: Option<RebalanceConfig>,
+    pub task_id: Option<TaskId>,
+    pub last_run_at: Option<u64>,
+}
+
+/// Works out the trades that bring the holdings back to their target weights. Overweight tokens
+/// are sold into the quote currency first, and then the quote currency buys the underweight
+/// ones. Only Sonic balances can be traded, so wallet balances are valued but never sold.
+pub fn plan(config: &RebalanceConfig, mut holdings: Vec<Holding>) -> RebalancePlan {
+    let total_value = holdings
+        .iter()
+        .fold(Nat::from(0), |sum, it| sum + it.value.clone());
+
+    let mut max_drift_bps = 0;
+    for holding in &mut holdings {
+        holding.weight_bps = weight_bps(&holding.value, &total_value);
+
+        let drift_bps = holding.weight_bps.abs_diff(holding.target_weight_bps);
+        max_drift_bps = max_drift_bps.max(drift_bps);
+    }
+
+    let needed = max_drift_bps > config.drift_threshold_bps;
+    let mut sells = Vec::new();
+    let mut buys = Vec::new();
+
+    if needed {
+        for holding in &holdings {
+            if holding.currency == config.quote_currency {
+                continue;
+            }
+
+            let target_value = total_value.clone() * holding.target_weight_bps / BPS;
+
+            if holding.value > target_value {
+                let total_balance = holding.sonic_balance.clone() + holding.wallet_balance.clone();
+                let excess_value = holding.value.clone() - target_value;
+                let amount = (total_balance * excess_value / holding.value.clone())
+                    .min(holding.sonic_balance.clone());
+
+                if amount.0 != 0u64.into() {
+                    sells.push(market_order(
+                        &holding.currency,
+                        &config.quote_currency,
+                        amount,
+                    ));
+                }
+            } else if target_value > holding.value {
+                let amount = target_value - holding.value.clone();
+
+                buys.push(market_order(
+                    &config.quote_currency,
+                    &holding.currency,
+                    amount,
+                ));
+            }
+        }
+    }
+
+    sells.append(&mut buys);
+
+    RebalancePlan {
+        total_value,
+        holdings,
+        max_drift_bps,
+        needed,
+        trades: sells,
+    }
+}
+
+fn weight_bps(value: &Nat, total_value: &Nat) -> u64 {
+    if total_value.0 == 0u64.into() {
+        return 0;
+    }
+
+    let weight = value.clone() * BPS / total_value.clone();
+
+    weight.0.to_u64().unwrap_or(BPS)
+}
+
+fn market_order(give_currency: &str, take_currency: &str, amount: Nat) -> MarketOrder {
+    MarketOrder {
+        give_currency: give_currency.to_string(),
+        take_currency: take_currency.to_string(),
+        directive: OrderDirective::GiveExact(amount),
+        max_slippage_bps: None,
+        deadline_secs: None,
+        max_price_impact_bps: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::TargetWeight;
+
+    fn config(targets: &[(&str, u64)]) -> RebalanceConfig {
+        RebalanceConfig {
+            targets: targets
+                .iter()
+                .map(|(currency, weight_bps)| TargetWeight {
+                    currency: currency.to_string(),
+                    weight_bps: *weight_bps,
+                })
+                .collect(),
+            quote_currency: String::from("WICP"),
+            drift_threshold_bps: 500,
+            interval_secs: 60,
+        }
+    }
+
+    fn holding(
+        currency: &str,
+        sonic_balance: u64,
+        wallet_balance: u64,
+        value: u64,
+        target_weight_bps: u64,
+    ) -> Holding {
+        Holding {
+            currency: currency.to_string(),
+            sonic_balance: Nat::from(sonic_balance),
+            wallet_balance: Nat::from(wallet_balance),
+            value: Nat::from(value),
+            weight_bps: 0,
+            target_weight_bps,
+        }
+    }
+
+    fn trades(plan: &RebalancePlan) -> Vec<(String, String, Nat)> {
+        plan.trades
+            .iter()
+            .map(|it| match &it.directive {
+                OrderDirective::GiveExact(amount) => (
+                    it.give_currency.clone(),
+                    it.take_currency.clone(),
+                    amount.clone(),
+                ),
+                OrderDirective::TakeExact(_) => panic!("Rebalancing only gives exact amounts"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_drift_needs_no_trades() {
+        let plan = plan(
+            &config(&[("WICP", 5_000), ("XTC", 5_000)]),
+            vec![
+                holding("WICP", 4_800, 0, 4_800, 5_000),
+                holding("XTC", 260, 0, 5_200, 5_000),
+            ],
+        );
+
+        assert_eq!(plan.total_value, Nat::from(10_000));
+        assert_eq!(plan.holdings[0].weight_bps, 4_800);
+        assert_eq!(plan.holdings[1].weight_bps, 5_200);
+        assert_eq!(plan.max_drift_bps, 200);
+        assert!(!plan.needed);
+        assert!(plan.trades.is_empty());
+    }
+
+    #[test]
+    fn overweight_tokens_are_sold_before_underweight_ones_are_bought() {
+        let plan = plan(
+            &config(&[("WICP", 2_000), ("XTC", 4_000), ("ABC", 4_000)]),
+            vec![
+                holding("ABC", 100, 0, 2_000, 4_000),
+                holding("WICP", 2_000, 0, 2_000, 2_000),
+                holding("XTC", 300, 0, 6_000, 4_000),
+            ],
+        );
+
+        assert!(plan.needed);
+        assert_eq!(plan.max_drift_bps, 2_000);
+        assert_eq!(
+            trades(&plan),
+            vec![
+                (String::from("XTC"), String::from("WICP"), Nat::from(100)),
+                (String::from("WICP"), String::from("ABC"), Nat::from(2_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn wallet_balances_are_never_sold() {
+        let plan = plan(
+            &config(&[("WICP", 5_000), ("XTC", 5_000)]),
+            vec![
+                holding("WICP", 2_000, 0, 2_000, 5_000),
+                holding("XTC", 50, 250, 6_000, 5_000),
+            ],
+        );
+
+        assert_eq!(
+            trades(&plan),
+            vec![(String::from("XTC"), String::from("WICP"), Nat::from(50))]
+        );
+    }
+
+    #[test]
+    fn empty_portfolio_has_nothing_to_trade() {
+        let plan = plan(
+            &config(&[("WICP", 5_000), ("XTC", 5_000)]),
+            vec![
+                holding("WICP", 0, 0, 0, 5_000),
+                holding("XTC", 0, 0, 0, 5_000),
+            ],
+        );
+
+        assert_eq!(plan.total_value, Nat::from(0));
+        assert!(plan.holdings.iter().all(|it| it.weight_bps == 0));
+        assert!(plan.trades.is_empty());
+    }
+}
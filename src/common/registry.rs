@@ -0,0 +1,99 @@
+use crate::common::errors::{BotError, BotResult};
+use crate::common::types::{BotConfig, Currency, TokenInfo, TokenMetadata};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use std::collections::HashMap;
+
+/// DIP20 tokens the bot is allowed to trade, keyed by their symbol
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct TokenRegistry {
+    pub tokens: HashMap<Currency, TokenInfo>,
+}
+
+impl TokenRegistry {
+    pub fn get(&self, currency: &Currency) -> BotResult<&TokenInfo> {
+        self.tokens
+            .get(currency)
+            .ok_or_else(|| BotError::TokenNotRegistered(currency.clone()))
+    }
+
+    pub fn canister_id(&self, currency: &Currency) -> BotResult<Principal> {
+        self.get(currency).map(|it| it.canister_id)
+    }
+
+    /// Adds a token, its metadata is fetched on first use when `None`
+    pub fn register(
+        &mut self,
+        currency: Currency,
+        canister_id: Principal,
+        metadata: Option<TokenMetadata>,
+    ) -> BotResult<&TokenInfo> {
+        if let Some(existing) = self.tokens.get(&currency) {
+            if existing.canister_id != canister_id {
+                return Err(BotError::InvalidArgument(format!(
+                    "Symbol {} is already taken by {}",
+                    currency, existing.canister_id
+                )));
+            }
+        }
+
+        let info = TokenInfo {
+            symbol: currency.clone(),
+            canister_id,
+            metadata,
+        };
+
+        self.tokens.insert(currency.clone(), info);
+
+        self.get(&currency)
+    }
+
+    /// Points XTC and WICP entries to canisters from the config, metadata is refetched on next use
+    pub fn set_well_known(&mut self, config: &BotConfig) {
+        for (symbol, canister_id) in [("XTC", config.xtc_canister), ("WICP", config.wicp_canister)]
+        {
+            let currency = String::from(symbol);
+
+            let unchanged =
+                matches!(self.tokens.get(&currency), Some(it) if it.canister_id == canister_id);
+            if unchanged {
+                continue;
+            }
+
+            self.tokens.insert(
+                currency.clone(),
+                TokenInfo {
+                    symbol: currency,
+                    canister_id,
+                    metadata: None,
+                },
+            );
+        }
+    }
+
+    pub fn unregister(&mut self, currency: &Currency) -> BotResult<TokenInfo> {
+        self.tokens
+            .remove(currency)
+            .ok_or_else(|| BotError::TokenNotRegistered(currency.clone()))
+    }
+
+    pub fn set_metadata(&mut self, currency: &Currency, metadata: TokenMetadata) {
+        if let Some(info) = self.tokens.get_mut(currency) {
+            info.metadata = Some(metadata);
+        }
+    }
+
+    /// Symbols keyed by the textual canister id, the way Sonic refers to tokens
+    pub fn symbols_by_token_id(&self) -> HashMap<String, Currency> {
+        self.tokens
+            .values()
+            .map(|it| (it.canister_id.to_text(), it.symbol.clone()))
+            .collect()
+    }
+
+    pub fn list(&self) -> Vec<TokenInfo> {
+        let mut tokens: Vec<_> = self.tokens.values().cloned().collect();
+        tokens.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        tokens
+    }
+}
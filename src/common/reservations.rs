@@ -0,0 +1,103 @@
+use crate::common::types::{Currency, GridId};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use ic_cron::types::TaskId;
+use std::collections::HashMap;
+
+/// Keeps track of Sonic funds locked by pending limit orders and grid levels, so they can't be
+/// spent twice
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ReservationLedger {
+    pub reserved: HashMap<Currency, Nat>,
+    pub by_order: HashMap<TaskId, (Currency, Nat)>,
+    pub by_grid_level: HashMap<(GridId, u64), (Currency, Nat)>,
+}
+
+impl ReservationLedger {
+    pub fn reserved(&self, currency: &Currency) -> Nat {
+        self.reserved.get(currency).cloned().unwrap_or_default()
+    }
+
+    pub fn available(&self, currency: &Currency, balance: Nat) -> Nat {
+        let reserved = self.reserved(currency);
+
+        if balance > reserved {
+            balance - reserved
+        } else {
+            Nat::from(0)
+        }
+    }
+
+    pub fn reserve(&mut self, task_id: TaskId, currency: Currency, amount: Nat) {
+        *self.reserved.entry(currency.clone()).or_default() += amount.clone();
+        self.by_order.insert(task_id, (currency, amount));
+    }
+
+    /// Locks what a grid level is buying with or holds, on top of whatever it has locked already
+    pub fn reserve_grid_level(
+        &mut self,
+        grid_id: GridId,
+        level: u64,
+        currency: Currency,
+        amount: Nat,
+    ) {
+        self.release_grid_level(grid_id, level);
+
+        *self.reserved.entry(currency.clone()).or_default() += amount.clone();
+        self.by_grid_level
+            .insert((grid_id, level), (currency, amount));
+    }
+
+    /// Hands the funds of one order over to another one locking the same currency, returns false
+    /// if they lock different ones
+    pub fn transfer(&mut self, from: TaskId, to: TaskId) -> bool {
+        let same_currency = matches!(
+            (self.by_order.get(&from), self.by_order.get(&to)),
+            (Some(from), Some(to)) if from.0 == to.0
+        );
+        if !same_currency {
+            return false;
+        }
+
+        let (_, amount) = self.by_order.remove(&from).unwrap();
+        self.by_order.get_mut(&to).unwrap().1 += amount;
+
+        true
+    }
+
+    pub fn release(&mut self, task_id: TaskId) -> Option<(Currency, Nat)> {
+        let (currency, amount) = self.by_order.remove(&task_id)?;
+        self.unlock(&currency, &amount);
+
+        Some((currency, amount))
+    }
+
+    pub fn release_grid_level(&mut self, grid_id: GridId, level: u64) {
+        if let Some((currency, amount)) = self.by_grid_level.remove(&(grid_id, level)) {
+            self.unlock(&currency, &amount);
+        }
+    }
+
+    /// Frees everything the grid has locked
+    pub fn release_grid(&mut self, grid_id: GridId) {
+        let levels: Vec<_> = self
+            .by_grid_level
+            .keys()
+            .filter(|(id, _)| *id == grid_id)
+            .cloned()
+            .collect();
+
+        for (grid_id, level) in levels {
+            self.release_grid_level(grid_id, level);
+        }
+    }
+
+    fn unlock(&mut self, currency: &Currency, amount: &Nat) {
+        let total = self.reserved(currency);
+        if total > *amount {
+            self.reserved
+                .insert(currency.clone(), total - amount.clone());
+        } else {
+            self.reserved.remove(currency);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use crate::clients::sonic::SonicPairInfo;
+use crate::common::errors::{BotError, BotResult};
+use crate::common::quote;
+use crate::common::registry::TokenRegistry;
+use crate::common::types::{Currency, OrderDirective, Route};
+use ic_cdk::export::candid::Nat;
+use std::collections::HashMap;
+
+/// Sonic accepts longer paths, but every hop costs a fee and rarely pays off beyond three
+pub const MAX_HOPS: usize = 3;
+
+/// Sonic pools between registered tokens, with reserves stored for both swap directions
+pub struct PoolGraph {
+    reserves: HashMap<(Currency, Currency), (Nat, Nat)>,
+    neighbours: HashMap<Currency, Vec<Currency>>,
+}
+
+impl PoolGraph {
+    /// Pools with unregistered tokens are left out
+    pub fn new(pairs: Vec<SonicPairInfo>, tokens: &TokenRegistry) -> Self {
+        let symbols = tokens.symbols_by_token_id();
+
+        let mut graph = PoolGraph {
+            reserves: HashMap::new(),
+            neighbours: HashMap::new(),
+        };
+
+        for pair in pairs {
+            let (token0, token1) = match (symbols.get(&pair.token0), symbols.get(&pair.token1)) {
+                (Some(token0), Some(token1)) => (token0.clone(), token1.clone()),
+                _ => continue,
+            };
+
+            graph.reserves.insert(
+                (token0.clone(), token1.clone()),
+                (pair.reserve0.clone(), pair.reserve1.clone()),
+            );
+            graph.reserves.insert(
+                (token1.clone(), token0.clone()),
+                (pair.reserve1, pair.reserve0),
+            );
+
+            graph
+                .neighbours
+                .entry(token0.clone())
+                .or_default()
+                .push(token1.clone());
+            graph.neighbours.entry(token1).or_default().push(token0);
+        }
+
+        graph
+    }
+
+    /// Every path of at most `MAX_HOPS` pools that doesn't visit a token twice, shortest first
+    pub fn paths(&self, give_currency: &Currency, take_currency: &Currency) -> Vec<Vec<Currency>> {
+        let mut paths = Vec::new();
+        let mut path = vec![give_currency.clone()];
+
+        self.collect_paths(take_currency, &mut path, &mut paths);
+        paths.sort_by_key(|it| it.len());
+
+        paths
+    }
+
+    fn collect_paths(
+        &self,
+        take_currency: &Currency,
+        path: &mut Vec<Currency>,
+        paths: &mut Vec<Vec<Currency>>,
+    ) {
+        let last = path.last().unwrap().clone();
+
+        if last == *take_currency {
+            paths.push(path.clone());
+            return;
+        }
+
+        if path.len() > MAX_HOPS {
+            return;
+        }
+
+        for next in self.neighbours.get(&last).into_iter().flatten() {
+            if path.contains(next) {
+                continue;
+            }
+
+            path.push(next.clone());
+            self.collect_paths(take_currency, path, paths);
+            path.pop();
+        }
+    }
+
+    /// Every cycle through exactly three pools that starts and ends with the token
+    pub fn triangles(&self, currency: &Currency) -> Vec<Vec<Currency>> {
+        let neighbours = |it: &Currency| self.neighbours.get(it).into_iter().flatten();
+        let mut cycles = Vec::new();
+
+        for first in neighbours(currency) {
+            for second in neighbours(first) {
+                if second != currency
+                    && self
+                        .reserves
+                        .contains_key(&(second.clone(), currency.clone()))
+                {
+                    cycles.push(vec![
+                        currency.clone(),
+                        first.clone(),
+                        second.clone(),
+                        currency.clone(),
+                    ]);
+                }
+            }
+        }
+
+        cycles
+    }
+
+    /// Input and output reserves of each hop of the path
+    pub fn path_reserves(&self, path: &[Currency]) -> Option<Vec<(Nat, Nat)>> {
+        path.windows(2)
+            .map(|hop| {
+                self.reserves
+                    .get(&(hop[0].clone(), hop[1].clone()))
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// The path that takes the most for `GiveExact` or gives the least for `TakeExact`, the
+    /// shorter one wins a tie
+    pub fn best_route(
+        &self,
+        give_currency: &Currency,
+        take_currency: &Currency,
+        directive: &OrderDirective,
+    ) -> BotResult<Route> {
+        let paths = self.paths(give_currency, take_currency);
+
+        if paths.is_empty() {
+            return Err(BotError::PairNotFound {
+                give_currency: give_currency.clone(),
+                take_currency: take_currency.clone(),
+            });
+        }
+
+        let mut best: Option<Route> = None;
+
+        for path in paths {
+            let reserves = match self.path_reserves(&path) {
+                Some(reserves) => reserves,
+                None => continue,
+            };
+            let quote = match quote::quote_path(directive, &reserves) {
+                Some(quote) => quote,
+                None => continue,
+            };
+
+            let is_better = match (&best, directive) {
+                (None, _) => true,
+                (Some(best), OrderDirective::GiveExact(_)) => {
+                    quote.take_amount > best.quote.take_amount
+                }
+                (Some(best), OrderDirective::TakeExact(_)) => {
+                    quote.give_amount < best.quote.give_amount
+                }
+            };
+
+            if is_better {
+                best = Some(Route {
+                    path,
+                    reserves,
+                    quote,
+                });
+            }
+        }
+
+        best.ok_or(BotError::InsufficientLiquidity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::export::candid::{Int, Principal};
+
+    fn registry() -> TokenRegistry {
+        let mut tokens = TokenRegistry::default();
+        for (i, symbol) in ["A", "B", "C"].iter().enumerate() {
+            tokens
+                .register(
+                    String::from(*symbol),
+                    Principal::from_slice(&[i as u8 + 1]),
+                    None,
+                )
+                .unwrap();
+        }
+
+        tokens
+    }
+
+    fn pair(tokens: &TokenRegistry, token0: &str, token1: &str, reserve: u64) -> SonicPairInfo {
+        let token0 = tokens.canister_id(&String::from(token0)).unwrap().to_text();
+        let token1 = tokens.canister_id(&String::from(token1)).unwrap().to_text();
+        let id = format!("{}:{}", token0, token1);
+
+        SonicPairInfo {
+            id: id.clone(),
+            token0,
+            token1,
+            creator: Principal::anonymous(),
+            reserve0: Nat::from(reserve),
+            reserve1: Nat::from(reserve),
+            price0CumulativeLast: Nat::from(0),
+            price1CumulativeLast: Nat::from(0),
+            kLast: Nat::from(0),
+            blockTimestampLast: Int::from(0),
+            totalSupply: Nat::from(reserve),
+            lptoken: id,
+        }
+    }
+
+    fn currencies(symbols: &[&str]) -> Vec<Currency> {
+        symbols.iter().map(|it| String::from(*it)).collect()
+    }
+
+    #[test]
+    fn paths_are_listed_shortest_first() {
+        let tokens = registry();
+        let graph = PoolGraph::new(
+            vec![
+                pair(&tokens, "A", "B", 1_000),
+                pair(&tokens, "B", "C", 1_000),
+                pair(&tokens, "C", "A", 1_000),
+            ],
+            &tokens,
+        );
+
+        let paths = graph.paths(&String::from("A"), &String::from("C"));
+
+        assert_eq!(
+            paths,
+            vec![currencies(&["A", "C"]), currencies(&["A", "B", "C"])]
+        );
+        assert_eq!(graph.triangles(&String::from("A")).len(), 2);
+    }
+
+    #[test]
+    fn best_route_avoids_a_shallow_pool() {
+        let tokens = registry();
+        let graph = PoolGraph::new(
+            vec![
+                pair(&tokens, "A", "B", 1_000_000_000),
+                pair(&tokens, "B", "C", 1_000_000_000),
+                pair(&tokens, "A", "C", 100_000),
+            ],
+            &tokens,
+        );
+
+        let route = graph
+            .best_route(
+                &String::from("A"),
+                &String::from("C"),
+                &OrderDirective::GiveExact(Nat::from(10_000u64)),
+            )
+            .unwrap();
+
+        assert_eq!(route.path, currencies(&["A", "B", "C"]));
+        assert_eq!(route.reserves.len(), 2);
+    }
+
+    #[test]
+    fn unknown_and_unconnected_tokens_have_no_route() {
+        let tokens = registry();
+        let graph = PoolGraph::new(vec![pair(&tokens, "A", "B", 1_000)], &tokens);
+
+        let result = graph.best_route(
+            &String::from("A"),
+            &String::from("C"),
+            &OrderDirective::GiveExact(Nat::from(10u64)),
+        );
+
+        assert!(matches!(result, Err(BotError::PairNotFound { .. })));
+    }
+}
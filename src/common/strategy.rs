@@ -0,0 +1,165 @@
+use crate::common::quote;
+use crate::common::router::PoolGraph;
+use crate::common::types::{
+    Currency, LimitStrategy, MarketOrder, StrategyId, StrategyInstance, StrategyKind, TradeRecord,
+};
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use std::collections::HashMap;
+
+/// What strategies see of the market, taken once per heartbeat and shared by all of them
+pub struct MarketSnapshot {
+    pub timestamp: u64,
+    pub pools: PoolGraph,
+    /// Sonic balances of registered tokens, without what pending orders have reserved
+    pub balances: HashMap<Currency, Nat>,
+    pub decimals: HashMap<Currency, u8>,
+}
+
+impl MarketSnapshot {
+    pub fn balance(&self, currency: &Currency) -> Nat {
+        self.balances
+            .get(currency)
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0))
+    }
+
+    /// Price of the order if it was executed right now, the same way scheduled orders see it
+    pub fn quoted_price(&self, order: &MarketOrder) -> Option<f64> {
+        let route = self
+            .pools
+            .best_route(&order.give_currency, &order.take_currency, &order.directive)
+            .ok()?;
+        let price = quote::price(&route.quote.give_amount, &route.quote.take_amount).to_f64()?;
+
+        Some(price * self.decimals_modifier(&order.give_currency, &order.take_currency)?)
+    }
+
+    pub fn decimals_modifier(
+        &self,
+        give_currency: &Currency,
+        take_currency: &Currency,
+    ) -> Option<f64> {
+        let give_decimals = *self.decimals.get(give_currency)? as i32;
+        let take_decimals = *self.decimals.get(take_currency)? as i32;
+
+        Some(10f64.powi(give_decimals - take_decimals))
+    }
+}
+
+pub enum StrategyAction {
+    Swap(MarketOrder),
+}
+
+/// Something that trades on its own. Each run a strategy gets a fresh snapshot and returns what
+/// it wants done, then it hears back about each of its actions in order. Changes a strategy makes
+/// to itself are persisted, so its state has to live in the strategy.
+pub trait Strategy {
+    fn on_tick(&mut self, snapshot: &MarketSnapshot) -> Vec<StrategyAction>;
+
+    fn on_action_result(
+        &mut self,
+        _action: &StrategyAction,
+        _result: Result<&TradeRecord, String>,
+    ) {
+    }
+}
+
+impl StrategyKind {
+    pub fn strategy_mut(&mut self) -> &mut dyn Strategy {
+        match self {
+            StrategyKind::Limit(strategy) => strategy,
+        }
+    }
+}
+
+impl Strategy for LimitStrategy {
+    fn on_tick(&mut self, snapshot: &MarketSnapshot) -> Vec<StrategyAction> {
+        if self.fills >= self.config.max_fills {
+            return Vec::new();
+        }
+
+        let order = &self.config.market_order;
+        self.last_price = snapshot.quoted_price(order);
+
+        match self.last_price {
+            Some(price) if self.config.condition.is_met(price) => {
+                vec![StrategyAction::Swap(order.clone())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_action_result(&mut self, _action: &StrategyAction, result: Result<&TradeRecord, String>) {
+        match result {
+            Ok(_) => {
+                self.fills += 1;
+                self.last_error = None;
+            }
+            Err(e) => self.last_error = Some(e),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct StrategyRegistry {
+    pub instances: HashMap<StrategyId, StrategyInstance>,
+    pub next_id: StrategyId,
+}
+
+impl StrategyRegistry {
+    pub fn add(&mut self, kind: StrategyKind, interval_secs: u64, now: u64) -> StrategyId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.instances.insert(
+            id,
+            StrategyInstance {
+                id,
+                kind,
+                enabled: true,
+                interval_secs,
+                last_run_at: 0,
+                created_at: now,
+            },
+        );
+
+        id
+    }
+
+    pub fn get(&self, id: StrategyId) -> Option<&StrategyInstance> {
+        self.instances.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: StrategyId) -> Option<&mut StrategyInstance> {
+        self.instances.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: StrategyId) -> Option<StrategyInstance> {
+        self.instances.remove(&id)
+    }
+
+    pub fn list(&self) -> Vec<StrategyInstance> {
+        let mut instances: Vec<_> = self.instances.values().cloned().collect();
+        instances.sort_by_key(|it| it.id);
+
+        instances
+    }
+
+    /// Enabled instances whose interval has passed, they are marked as run at `now`
+    pub fn take_due(&mut self, now: u64) -> Vec<StrategyId> {
+        let mut due = Vec::new();
+
+        for instance in self.instances.values_mut() {
+            if instance.enabled
+                && now >= instance.last_run_at + instance.interval_secs * 1_000_000_000
+            {
+                instance.last_run_at = now;
+                due.push(instance.id);
+            }
+        }
+        due.sort_unstable();
+
+        due
+    }
+}
@@ -1,9 +1,19 @@
-use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use crate::common::errors::{BotError, BotResult};
+use crate::common::quote::{to_decimal, BPS};
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_cron::types::TaskId;
 
 #[derive(CandidType, Deserialize)]
 pub enum Order {
     Market(MarketOrder),
     Limit(LimitOrder),
+    StopLoss(ProtectiveOrder),
+    TakeProfit(ProtectiveOrder),
+    TrailingStop(TrailingStopOrder),
+    Oco(Vec<ScheduledOrder>),
+    Twap(TwapOrder),
+    Recurring(RecurringOrder),
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -11,12 +21,490 @@ pub struct MarketOrder {
     pub give_currency: Currency,
     pub take_currency: Currency,
     pub directive: OrderDirective,
+    pub max_slippage_bps: Option<u64>,
+    pub deadline_secs: Option<u64>,
+    pub max_price_impact_bps: Option<u64>,
 }
 
+impl MarketOrder {
+    /// Limits set for this order, with canister-wide defaults for the ones it leaves empty
+    pub fn limits(&self, defaults: &TradingLimits) -> TradingLimits {
+        TradingLimits {
+            max_slippage_bps: self.max_slippage_bps.unwrap_or(defaults.max_slippage_bps),
+            deadline_secs: self.deadline_secs.unwrap_or(defaults.deadline_secs),
+            max_price_impact_bps: self
+                .max_price_impact_bps
+                .unwrap_or(defaults.max_price_impact_bps),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct TradingLimits {
+    pub max_slippage_bps: u64,
+    pub deadline_secs: u64,
+    pub max_price_impact_bps: u64,
+}
+
+impl Default for TradingLimits {
+    fn default() -> Self {
+        TradingLimits {
+            max_slippage_bps: 100,
+            deadline_secs: 20,
+            max_price_impact_bps: 500,
+        }
+    }
+}
+
+impl TradingLimits {
+    pub fn validate(&self) -> BotResult<()> {
+        if self.max_slippage_bps > BPS {
+            return Err(BotError::InvalidArgument(String::from(
+                "max_slippage_bps can't be more than 10000",
+            )));
+        }
+
+        if self.max_price_impact_bps > BPS {
+            return Err(BotError::InvalidArgument(String::from(
+                "max_price_impact_bps can't be more than 10000",
+            )));
+        }
+
+        if self.deadline_secs == 0 {
+            return Err(BotError::InvalidArgument(String::from(
+                "deadline_secs should be positive",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Executes `market_order` once the price it would fill at through the best route meets the
+/// target, in the same units as protective orders
 #[derive(CandidType, Deserialize, Clone)]
 pub struct LimitOrder {
     pub target_price_condition: TargetPrice,
     pub market_order: MarketOrder,
+    pub time_in_force: Option<TimeInForce>,
+}
+
+impl LimitOrder {
+    pub fn time_in_force(&self) -> TimeInForce {
+        self.time_in_force.unwrap_or_default()
+    }
+}
+
+/// How long a limit order keeps waiting for its price, good till cancelled if not set
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancelled,
+    /// Canister time in nanoseconds
+    ExpiresAt(u64),
+    /// How many times the price may be checked without meeting the condition
+    MaxChecks(u64),
+}
+
+impl TimeInForce {
+    pub fn validate(&self, now: u64) -> BotResult<()> {
+        match self {
+            TimeInForce::ExpiresAt(expires_at) if *expires_at <= now => Err(
+                BotError::InvalidArgument(String::from("The order would expire immediately")),
+            ),
+            TimeInForce::MaxChecks(0) => Err(BotError::InvalidArgument(String::from(
+                "An order should be checked at least once",
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64, checks_made: u64) -> bool {
+        match self {
+            TimeInForce::GoodTillCancelled => false,
+            TimeInForce::ExpiresAt(expires_at) => now >= *expires_at,
+            TimeInForce::MaxChecks(max_checks) => checks_made >= *max_checks,
+        }
+    }
+}
+
+/// Closes a position held in `give_currency`. The trigger is compared against the price the whole
+/// `market_order` would fill at right now, in the same units `get_swap_price` uses - how many give
+/// tokens one take token costs. So a stop-loss fires once that price rises to `trigger_price`,
+/// and a take-profit fires once it drops to `trigger_price`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProtectiveOrder {
+    pub trigger_price: f64,
+    pub market_order: MarketOrder,
+}
+
+/// Closes a position held in `give_currency` once its value falls by `distance` from the best
+/// value seen since the order was placed. Unlike other orders it looks at the value of the
+/// position - how many take tokens one give token brings if the whole `market_order` is filled.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TrailingStopOrder {
+    pub distance: TrailingDistance,
+    pub market_order: MarketOrder,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TrailingDistance {
+    Bps(u64),
+    Absolute(f64),
+}
+
+impl TrailingDistance {
+    pub fn validate(&self) -> BotResult<()> {
+        let valid = match self {
+            TrailingDistance::Bps(bps) => *bps > 0 && *bps < BPS,
+            TrailingDistance::Absolute(amount) => amount.is_finite() && *amount > 0f64,
+        };
+
+        if !valid {
+            return Err(BotError::InvalidArgument(String::from(
+                "Trailing distance should be positive and less than 10000 bps",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The value at which the stop fires, given the best value seen so far
+    pub fn stop_value(&self, peak_value: f64) -> f64 {
+        match self {
+            TrailingDistance::Bps(bps) => peak_value * (BPS - bps) as f64 / BPS as f64,
+            TrailingDistance::Absolute(amount) => peak_value - amount,
+        }
+    }
+}
+
+/// Splits a `GiveExact` market order into `slices` equal swaps made each `interval_secs`, so every
+/// single swap moves the pool less. A slice is skipped if its quoted price doesn't meet
+/// `price_limit`, the skipped amount is not made up for later.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TwapOrder {
+    pub market_order: MarketOrder,
+    pub slices: u64,
+    pub interval_secs: u64,
+    pub price_limit: Option<TargetPrice>,
+}
+
+impl TwapOrder {
+    pub fn validate(&self) -> BotResult<()> {
+        let total_amount = match &self.market_order.directive {
+            OrderDirective::GiveExact(amount) => amount,
+            OrderDirective::TakeExact(_) => {
+                return Err(BotError::InvalidArgument(String::from(
+                    "A TWAP order can only give an exact amount",
+                )))
+            }
+        };
+
+        if self.slices == 0 || self.interval_secs == 0 {
+            return Err(BotError::InvalidArgument(String::from(
+                "A TWAP order needs at least one slice and a non-zero interval",
+            )));
+        }
+
+        if total_amount.0 < self.slices.into() {
+            return Err(BotError::InvalidArgument(String::from(
+                "A TWAP order can't give less than one token unit per slice",
+            )));
+        }
+
+        if let Some(price_limit) = &self.price_limit {
+            price_limit.validate()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn total_amount(&self) -> Nat {
+        match &self.market_order.directive {
+            OrderDirective::GiveExact(amount) | OrderDirective::TakeExact(amount) => amount.clone(),
+        }
+    }
+
+    /// How much the slice gives, the last one also gives the rounding remainder
+    pub fn slice_amount(&self, index: u64) -> Nat {
+        let slice_amount = self.total_amount() / self.slices;
+
+        if index + 1 == self.slices {
+            self.total_amount() - slice_amount * (self.slices - 1)
+        } else {
+            slice_amount
+        }
+    }
+
+    pub fn slice(&self, index: u64) -> MarketOrder {
+        MarketOrder {
+            directive: OrderDirective::GiveExact(self.slice_amount(index)),
+            ..self.market_order.clone()
+        }
+    }
+}
+
+/// Swaps a fixed `GiveExact` amount each `interval_secs`, `runs` times or until cancelled. A run
+/// is skipped if its quoted price is above `max_price`, and no run happens after `ends_at`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RecurringOrder {
+    pub market_order: MarketOrder,
+    pub interval_secs: u64,
+    pub runs: Option<u64>,
+    pub ends_at: Option<u64>,
+    pub max_price: Option<f64>,
+}
+
+impl RecurringOrder {
+    pub fn validate(&self, now: u64) -> BotResult<()> {
+        if let OrderDirective::TakeExact(_) = &self.market_order.directive {
+            return Err(BotError::InvalidArgument(String::from(
+                "A recurring order can only give an exact amount",
+            )));
+        }
+
+        if self.interval_secs == 0 || self.runs == Some(0) {
+            return Err(BotError::InvalidArgument(String::from(
+                "A recurring order needs at least one run and a non-zero interval",
+            )));
+        }
+
+        if matches!(self.ends_at, Some(ends_at) if ends_at <= now) {
+            return Err(BotError::InvalidArgument(String::from(
+                "The order would end immediately",
+            )));
+        }
+
+        if matches!(self.max_price, Some(max_price) if !(max_price.is_finite() && max_price > 0f64))
+        {
+            return Err(BotError::InvalidArgument(String::from(
+                "The price ceiling should be positive",
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn has_ended(&self, now: u64) -> bool {
+        matches!(self.ends_at, Some(ends_at) if now >= ends_at)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum RunOutcome {
+    Filled {
+        trade_id: u64,
+        give_amount: Nat,
+        take_amount: Nat,
+        realised_price: f64,
+    },
+    Skipped {
+        quoted_price: f64,
+    },
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderRun {
+    pub index: u64,
+    pub executed_at: u64,
+    pub outcome: RunOutcome,
+}
+
+/// Runs of an order that swaps on schedule rather than once its price is met
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderProgress {
+    pub runs_total: Option<u64>,
+    pub runs_started: u64,
+    pub runs: Vec<OrderRun>,
+    pub filled_give_amount: Nat,
+    pub filled_take_amount: Nat,
+    /// Take-weighted average of the realised prices of filled runs
+    pub average_price: Option<f64>,
+}
+
+impl OrderProgress {
+    pub fn new(runs_total: Option<u64>) -> Self {
+        OrderProgress {
+            runs_total,
+            runs_started: 0,
+            runs: Vec::new(),
+            filled_give_amount: Nat::from(0),
+            filled_take_amount: Nat::from(0),
+            average_price: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.runs_total, Some(runs_total) if self.runs.len() as u64 >= runs_total)
+    }
+
+    /// An order that has run its course is filled if at least one of its runs was
+    pub fn final_status(&self) -> OrderStatus {
+        if self.filled_give_amount.0 > 0u64.into() {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::Expired
+        }
+    }
+
+    pub fn record(&mut self, run: OrderRun) {
+        if let RunOutcome::Filled {
+            give_amount,
+            take_amount,
+            realised_price,
+            ..
+        } = &run.outcome
+        {
+            let filled_before = to_decimal(&self.filled_take_amount).to_f64().unwrap();
+            let filled_now = to_decimal(take_amount).to_f64().unwrap();
+
+            self.average_price = Some(match self.average_price {
+                Some(average_price) if filled_before + filled_now > 0f64 => {
+                    (average_price * filled_before + realised_price * filled_now)
+                        / (filled_before + filled_now)
+                }
+                _ => *realised_price,
+            });
+
+            self.filled_give_amount += give_amount.clone();
+            self.filled_take_amount += take_amount.clone();
+        }
+
+        self.runs.push(run);
+    }
+}
+
+/// Orders waiting in the cron queue for their condition
+#[derive(CandidType, Deserialize, Clone)]
+pub enum ScheduledOrder {
+    Limit(LimitOrder),
+    StopLoss(ProtectiveOrder),
+    TakeProfit(ProtectiveOrder),
+    TrailingStop(TrailingStopOrder),
+    Twap(TwapOrder),
+    Recurring(RecurringOrder),
+}
+
+impl ScheduledOrder {
+    pub fn market_order(&self) -> &MarketOrder {
+        match self {
+            ScheduledOrder::Limit(order) => &order.market_order,
+            ScheduledOrder::StopLoss(order) | ScheduledOrder::TakeProfit(order) => {
+                &order.market_order
+            }
+            ScheduledOrder::TrailingStop(order) => &order.market_order,
+            ScheduledOrder::Twap(order) => &order.market_order,
+            ScheduledOrder::Recurring(order) => &order.market_order,
+        }
+    }
+
+    pub fn validate(&self, default_limits: &TradingLimits, now: u64) -> BotResult<()> {
+        self.market_order().limits(default_limits).validate()?;
+
+        match self {
+            ScheduledOrder::Limit(order) => order.time_in_force().validate(now)?,
+            ScheduledOrder::TrailingStop(order) => order.distance.validate()?,
+            ScheduledOrder::Twap(order) => order.validate()?,
+            ScheduledOrder::Recurring(order) => order.validate(now)?,
+            _ => {}
+        }
+
+        if let Some(condition) = self.trigger_condition() {
+            condition.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fixed price condition of the order, trailing stops don't have one
+    pub fn trigger_condition(&self) -> Option<TargetPrice> {
+        match self {
+            ScheduledOrder::Limit(order) => Some(order.target_price_condition.clone()),
+            ScheduledOrder::StopLoss(order) => Some(TargetPrice::MoreThan(order.trigger_price)),
+            ScheduledOrder::TakeProfit(order) => Some(TargetPrice::LessThan(order.trigger_price)),
+            ScheduledOrder::TrailingStop(_)
+            | ScheduledOrder::Twap(_)
+            | ScheduledOrder::Recurring(_) => None,
+        }
+    }
+
+    /// Whether a price-triggered order should be executed at `price`: the spot price for limit
+    /// orders and the quoted price of the whole order for the rest. Trailing stops keep their
+    /// best value so far in `peak`.
+    pub fn is_triggered(&self, price: f64, peak: &mut Option<f64>) -> bool {
+        match self {
+            ScheduledOrder::TrailingStop(trailing_stop) => {
+                let value = 1f64 / price;
+
+                // the first check happens right after placement, so it sets the initial peak
+                let peak = match peak {
+                    Some(peak) if *peak >= value => *peak,
+                    _ => *peak.insert(value),
+                };
+
+                value <= trailing_stop.distance.stop_value(peak)
+            }
+            _ => matches!(self.trigger_condition(), Some(condition) if condition.is_met(price)),
+        }
+    }
+
+    pub fn trade_origin(&self, task_id: TaskId) -> TradeOrigin {
+        match self {
+            ScheduledOrder::Limit(_) => TradeOrigin::Limit(task_id),
+            ScheduledOrder::StopLoss(_) => TradeOrigin::StopLoss(task_id),
+            ScheduledOrder::TakeProfit(_) => TradeOrigin::TakeProfit(task_id),
+            ScheduledOrder::TrailingStop(_) => TradeOrigin::TrailingStop(task_id),
+            ScheduledOrder::Twap(_) => TradeOrigin::Twap(task_id),
+            ScheduledOrder::Recurring(_) => TradeOrigin::Recurring(task_id),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum OcoStatus {
+    Active,
+    Filled(TaskId),
+    Failed(TaskId),
+    Cancelled,
+}
+
+pub type OcoGroupId = u64;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OcoGroup {
+    pub id: OcoGroupId,
+    pub legs: Vec<TaskId>,
+    pub status: OcoStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OcoGroupInfo {
+    pub group: OcoGroup,
+    pub pending_legs: Vec<OrderInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderInfo {
+    pub task_id: TaskId,
+    pub order: ScheduledOrder,
+    pub created_at: u64,
+    pub next_check_at: u64,
+    pub trailing_peak: Option<f64>,
+    pub checks_made: u64,
+    pub failed_attempts: u64,
+    pub oco_group_id: Option<OcoGroupId>,
+}
+
+/// Orders leave the queue once they are executed, cancelled or expired
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum OrderStatus {
+    Pending,
+    Executing,
+    Filled,
+    Failed,
+    Cancelled,
+    Expired,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -25,6 +513,29 @@ pub enum TargetPrice {
     LessThan(f64),
 }
 
+impl TargetPrice {
+    pub fn validate(&self) -> BotResult<()> {
+        let price = match self {
+            TargetPrice::MoreThan(price) | TargetPrice::LessThan(price) => *price,
+        };
+
+        if !(price.is_finite() && price > 0f64) {
+            return Err(BotError::InvalidArgument(String::from(
+                "A target price should be a positive number",
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_met(&self, price: f64) -> bool {
+        match self {
+            TargetPrice::MoreThan(target_price) => price >= *target_price,
+            TargetPrice::LessThan(target_price) => price <= *target_price,
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub enum OrderDirective {
     GiveExact(Nat),
@@ -32,7 +543,523 @@ pub enum OrderDirective {
 }
 
 #[derive(CandidType, Deserialize, Clone)]
-pub enum Currency {
-    XTC,
-    WICP,
+pub enum TradeOrigin {
+    Market,
+    Limit(TaskId),
+    StopLoss(TaskId),
+    TakeProfit(TaskId),
+    TrailingStop(TaskId),
+    Twap(TaskId),
+    Recurring(TaskId),
+    Grid(GridId),
+    Arbitrage(u64),
+    Rebalance,
+    Strategy(StrategyId),
+}
+
+impl TradeOrigin {
+    pub fn task_id(&self) -> Option<TaskId> {
+        match self {
+            TradeOrigin::Market => None,
+            TradeOrigin::Limit(task_id)
+            | TradeOrigin::StopLoss(task_id)
+            | TradeOrigin::TakeProfit(task_id)
+            | TradeOrigin::TrailingStop(task_id)
+            | TradeOrigin::Twap(task_id)
+            | TradeOrigin::Recurring(task_id) => Some(*task_id),
+            TradeOrigin::Grid(_)
+            | TradeOrigin::Arbitrage(_)
+            | TradeOrigin::Rebalance
+            | TradeOrigin::Strategy(_) => None,
+        }
+    }
+}
+
+pub type GridId = u64;
+
+/// Trades `base_currency` against `quote_currency` at `levels` evenly spaced prices from
+/// `lower_price` to `upper_price`, in quote tokens per base token. Each time the price crosses a
+/// level down, the grid buys `level_size` base tokens, and it sells them once the price crosses
+/// the next level up.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GridConfig {
+    pub base_currency: Currency,
+    pub quote_currency: Currency,
+    pub lower_price: f64,
+    pub upper_price: f64,
+    pub levels: u64,
+    pub level_size: Nat,
+}
+
+impl GridConfig {
+    pub fn validate(&self) -> BotResult<()> {
+        if self.base_currency == self.quote_currency {
+            return Err(BotError::InvalidArgument(String::from(
+                "A grid needs two different currencies",
+            )));
+        }
+
+        if !(self.lower_price.is_finite()
+            && self.upper_price.is_finite()
+            && self.lower_price > 0f64
+            && self.upper_price > self.lower_price)
+        {
+            return Err(BotError::InvalidArgument(String::from(
+                "Grid bounds should be finite and positive, and the upper one above the lower one",
+            )));
+        }
+
+        if self.levels < 2 || self.level_size.0 == 0u64.into() {
+            return Err(BotError::InvalidArgument(String::from(
+                "A grid needs at least two levels and a non-zero level size",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum GridStatus {
+    Running,
+    Paused,
+    Stopped,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GridPosition {
+    pub base_amount: Nat,
+    pub quote_spent: Nat,
+    pub bought_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GridLevel {
+    pub price: f64,
+    pub position: Option<GridPosition>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Grid {
+    pub id: GridId,
+    pub config: GridConfig,
+    pub status: GridStatus,
+    pub levels: Vec<GridLevel>,
+    pub last_price: Option<f64>,
+    pub last_checked_at: u64,
+    /// In quote tokens, summed over every position bought and sold again
+    pub realised_profit: Int,
+    pub round_trips: u64,
+    pub created_at: u64,
+}
+
+/// A trade a grid wants to make at one of its levels
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum GridAction {
+    Buy(u64),
+    Sell(u64),
+}
+
+/// Looks for cycles of three pools that turn `base_currency` into more of itself, trading at most
+/// `max_trade_size` and only if the cycle earns at least `min_profit_bps`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ArbitrageConfig {
+    pub enabled: bool,
+    pub base_currency: Currency,
+    pub min_profit_bps: u64,
+    pub max_trade_size: Nat,
+    pub scan_interval_secs: u64,
+}
+
+impl Default for ArbitrageConfig {
+    fn default() -> Self {
+        ArbitrageConfig {
+            enabled: false,
+            base_currency: String::from("XTC"),
+            min_profit_bps: 50,
+            max_trade_size: Nat::from(0),
+            scan_interval_secs: 60,
+        }
+    }
+}
+
+impl ArbitrageConfig {
+    pub fn validate(&self) -> BotResult<()> {
+        if self.scan_interval_secs == 0 {
+            return Err(BotError::InvalidArgument(String::from(
+                "Scan interval should be positive",
+            )));
+        }
+
+        if self.enabled && self.max_trade_size.0 == 0u64.into() {
+            return Err(BotError::InvalidArgument(String::from(
+                "Max trade size should be positive",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ArbitrageOutcome {
+    Found,
+    Taken { trade_id: u64, profit: Int },
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ArbitrageOpportunity {
+    pub id: u64,
+    pub found_at: u64,
+    pub path: Vec<Currency>,
+    pub amount_in: Nat,
+    pub quoted_amount_out: Nat,
+    pub profit_bps: u64,
+    pub outcome: ArbitrageOutcome,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ArbitrageLogPage {
+    pub entries: Vec<ArbitrageOpportunity>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TargetWeight {
+    pub currency: Currency,
+    pub weight_bps: u64,
+}
+
+/// Keeps the value of the tokens, measured in `quote_currency`, at the target weights. The
+/// portfolio is checked each `interval_secs` and rebalanced once any weight drifts further than
+/// `drift_threshold_bps` from its target.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RebalanceConfig {
+    pub targets: Vec<TargetWeight>,
+    pub quote_currency: Currency,
+    pub drift_threshold_bps: u64,
+    pub interval_secs: u64,
+}
+
+impl RebalanceConfig {
+    pub fn validate(&self) -> BotResult<()> {
+        let total_weight_bps: u64 = self.targets.iter().map(|it| it.weight_bps).sum();
+        if total_weight_bps != BPS {
+            return Err(BotError::InvalidArgument(String::from(
+                "Target weights should add up to 10000 bps",
+            )));
+        }
+
+        for (i, target) in self.targets.iter().enumerate() {
+            if self.targets[..i]
+                .iter()
+                .any(|it| it.currency == target.currency)
+            {
+                return Err(BotError::InvalidArgument(format!(
+                    "{} has more than one target weight",
+                    target.currency
+                )));
+            }
+        }
+
+        if self.drift_threshold_bps == 0 || self.drift_threshold_bps >= BPS {
+            return Err(BotError::InvalidArgument(String::from(
+                "Drift threshold should be positive and less than 10000 bps",
+            )));
+        }
+
+        if self.interval_secs == 0 {
+            return Err(BotError::InvalidArgument(String::from(
+                "Rebalancing interval should be positive",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A token of the portfolio, its value is in the quote currency
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Holding {
+    pub currency: Currency,
+    pub sonic_balance: Nat,
+    pub wallet_balance: Nat,
+    pub value: Nat,
+    pub weight_bps: u64,
+    pub target_weight_bps: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RebalancePlan {
+    pub total_value: Nat,
+    pub holdings: Vec<Holding>,
+    pub max_drift_bps: u64,
+    pub needed: bool,
+    pub trades: Vec<MarketOrder>,
+}
+
+pub type StrategyId = u64;
+
+/// Swaps `market_order` each time its quoted price meets `condition`, at most `max_fills` times
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LimitStrategyConfig {
+    pub condition: TargetPrice,
+    pub market_order: MarketOrder,
+    pub max_fills: u64,
+}
+
+impl LimitStrategyConfig {
+    pub fn validate(&self, default_limits: &TradingLimits) -> BotResult<()> {
+        self.market_order.limits(default_limits).validate()?;
+
+        if self.market_order.give_currency == self.market_order.take_currency {
+            return Err(BotError::InvalidArgument(String::from(
+                "A strategy can't swap a currency into itself",
+            )));
+        }
+
+        self.condition.validate()?;
+
+        if self.max_fills == 0 {
+            return Err(BotError::InvalidArgument(String::from(
+                "A limit strategy should be allowed at least one fill",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LimitStrategy {
+    pub config: LimitStrategyConfig,
+    pub fills: u64,
+    pub last_price: Option<f64>,
+    pub last_error: Option<String>,
+}
+
+/// What a strategy instance is created from
+#[derive(CandidType, Deserialize, Clone)]
+pub enum StrategyConfig {
+    Limit(LimitStrategyConfig),
+}
+
+impl StrategyConfig {
+    pub fn validate(&self, default_limits: &TradingLimits) -> BotResult<()> {
+        match self {
+            StrategyConfig::Limit(config) => config.validate(default_limits),
+        }
+    }
+
+    /// Currencies the strategy trades, they should all be registered
+    pub fn currencies(&self) -> Vec<Currency> {
+        match self {
+            StrategyConfig::Limit(config) => vec![
+                config.market_order.give_currency.clone(),
+                config.market_order.take_currency.clone(),
+            ],
+        }
+    }
+
+    /// A strategy in its initial state
+    pub fn build(self) -> StrategyKind {
+        match self {
+            StrategyConfig::Limit(config) => StrategyKind::Limit(LimitStrategy {
+                config,
+                fills: 0,
+                last_price: None,
+                last_error: None,
+            }),
+        }
+    }
+}
+
+/// A strategy together with its state, which survives upgrades like the rest of the canister state
+#[derive(CandidType, Deserialize, Clone)]
+pub enum StrategyKind {
+    Limit(LimitStrategy),
+}
+
+impl StrategyKind {
+    pub fn currencies(&self) -> Vec<Currency> {
+        match self {
+            StrategyKind::Limit(strategy) => vec![
+                strategy.config.market_order.give_currency.clone(),
+                strategy.config.market_order.take_currency.clone(),
+            ],
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StrategyInstance {
+    pub id: StrategyId,
+    pub kind: StrategyKind,
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub last_run_at: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TradeRecord {
+    pub id: u64,
+    pub origin: TradeOrigin,
+    pub give_currency: Currency,
+    pub take_currency: Currency,
+    pub give_amount: Nat,
+    pub take_amount: Nat,
+    pub path: Vec<Currency>,
+    pub quoted_price: f64,
+    pub realised_price: f64,
+    pub timestamp: u64,
+    pub sonic_receipt: Nat,
+    pub mode: Mode,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct TradeFilter {
+    pub currency: Option<Currency>,
+    pub task_id: Option<TaskId>,
+    pub from_timestamp: Option<u64>,
+    pub to_timestamp: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TradeHistoryPage {
+    pub records: Vec<TradeRecord>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SwapQuote {
+    pub give_amount: Nat,
+    pub take_amount: Nat,
+    pub fee: Nat,
+    pub price_impact_bps: u64,
+}
+
+/// Amounts the bot asked Sonic to add or remove, the pool may settle for slightly different ones
+/// within the slippage bounds
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LiquidityReceipt {
+    pub amount_a: Nat,
+    pub amount_b: Nat,
+    pub sonic_receipt: Nat,
+}
+
+/// LP tokens the bot holds in one pool, tokens are named by their symbols if registered
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LpPosition {
+    pub pair_id: String,
+    pub token0: Principal,
+    pub token1: Principal,
+    pub currency0: Option<Currency>,
+    pub currency1: Option<Currency>,
+    pub lp_balance: Nat,
+    pub total_supply: Nat,
+    pub amount0: Nat,
+    pub amount1: Nat,
+}
+
+/// Pool state and amounts at the moment liquidity was added, in the pool's token order
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LpEntry {
+    pub timestamp: u64,
+    pub reserve0: Nat,
+    pub reserve1: Nat,
+    pub total_supply: Nat,
+    pub k_last: Nat,
+    pub amount0: Nat,
+    pub amount1: Nat,
+    pub lp_minted: Nat,
+}
+
+/// Liquidity the bot has added to one pool and not removed yet
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LpRecord {
+    pub pair_id: String,
+    pub token0: Principal,
+    pub token1: Principal,
+    pub deposited0: Nat,
+    pub deposited1: Nat,
+    pub lp_amount: Nat,
+    /// Averaged over the entries, weighted by the LP tokens they minted
+    pub entry_root_k_per_lp: f64,
+    pub entries: Vec<LpEntry>,
+}
+
+/// Values are in token1 units at the current pool price. Impermanent loss and fee yield together
+/// make up the difference between the position and holding the deposited tokens.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LpReport {
+    pub pair_id: String,
+    pub currency0: Option<Currency>,
+    pub currency1: Option<Currency>,
+    pub lp_amount: Nat,
+    pub deposited0: Nat,
+    pub deposited1: Nat,
+    pub amount0: Nat,
+    pub amount1: Nat,
+    pub hold_value: f64,
+    pub lp_value: f64,
+    pub value_vs_hold_bps: i64,
+    pub impermanent_loss_bps: i64,
+    pub fee_yield_bps: i64,
+    pub entries: Vec<LpEntry>,
+}
+
+/// A way to swap one token into another through one or more Sonic pools
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Route {
+    pub path: Vec<Currency>,
+    /// Input and output reserves of each hop
+    pub reserves: Vec<(Nat, Nat)>,
+    pub quote: SwapQuote,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct BotConfig {
+    pub xtc_canister: Principal,
+    pub wicp_canister: Principal,
+    pub sonic_swap_canister: Principal,
+    pub controller: Principal,
+}
+
+impl BotConfig {
+    pub fn mainnet(controller: Principal) -> Self {
+        BotConfig {
+            xtc_canister: Principal::from_text("aanaa-xaaaa-aaaah-aaeiq-cai").unwrap(),
+            wicp_canister: Principal::from_text("utozz-siaaa-aaaam-qaaxq-cai").unwrap(),
+            sonic_swap_canister: Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap(),
+            controller,
+        }
+    }
+}
+
+/// Symbol of a token from the registry
+pub type Currency = String;
+
+/// In paper mode trades don't reach Sonic, they are filled at the quote against live reserves and
+/// settled in virtual balances instead
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum Mode {
+    #[default]
+    Live,
+    Paper,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub decimals: u8,
+    pub fee: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenInfo {
+    pub symbol: Currency,
+    pub canister_id: Principal,
+    pub metadata: Option<TokenMetadata>,
 }
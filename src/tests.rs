@@ -0,0 +1,1357 @@
+//! The order engine running against in-memory fakes of DIP20 tokens, Sonic and XTC
+
+use crate::clients::dip20::Dip20;
+use crate::clients::fake::{FakeLedger, FakeSonic, FakeXtc};
+use crate::clients::sonic::Sonic;
+use crate::clients::xtc::XTC;
+use crate::common::env::{set_env, Env};
+use crate::common::errors::BotError;
+use crate::common::legacy::{LegacyCurrency, LegacyLimitOrder, LegacyMarketOrder, LegacyState};
+use crate::common::quote;
+use crate::common::types::{
+    BotConfig, GridConfig, LimitOrder, MarketOrder, Mode, OcoStatus, Order, OrderDirective,
+    OrderStatus, ProtectiveOrder, RebalanceConfig, RecurringOrder, RunOutcome, ScheduledOrder,
+    TargetPrice, TargetWeight, TimeInForce, TrailingDistance, TrailingStopOrder, TwapOrder,
+};
+use crate::{
+    add_liquidity, add_order, cancel_oco_group, cancel_order, deposit, get_config, get_cron_state,
+    get_grid, get_mode, get_oco_group, get_order, get_order_progress, get_order_status, get_state,
+    get_trade_history, init, list_lp_positions, list_orders, migrate_legacy_state,
+    mint_xtc_with_own_cycles, my_reserved_balance, my_sonic_balance, register_token,
+    remove_liquidity, set_cron_state, set_mode, set_paper_balance, start_grid, start_rebalancing,
+    stop_grid, stop_rebalancing, tick, unregister_token, update_config, withdraw, State,
+    MAX_EXECUTION_ATTEMPTS, STATE,
+};
+use ic_cdk::export::candid::{decode_args, encode_args, Nat, Principal};
+use ic_cron::task_scheduler::TaskScheduler;
+use ic_cron::types::{Iterations, SchedulingInterval};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+const SECOND: u64 = 1_000_000_000;
+const RESERVE: u64 = 1_000_000_000;
+
+/// The canister state is global, so tests take turns
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct FakeEnv {
+    bot: Principal,
+    now: AtomicU64,
+    xtc: Arc<FakeLedger>,
+    wicp: Arc<FakeLedger>,
+    /// Known to Sonic, but neither registered with the bot nor traded in any pool yet
+    ghost: Arc<FakeLedger>,
+    ledgers: HashMap<Principal, Arc<FakeLedger>>,
+    sonic: FakeSonic,
+    xtc_minter: FakeXtc,
+    /// Answers for canisters the bot isn't set up with, rejecting every call
+    missing_ledger: Arc<FakeLedger>,
+    missing_sonic: FakeSonic,
+    missing_xtc: FakeXtc,
+    logs: Mutex<Vec<String>>,
+}
+
+impl FakeEnv {
+    fn advance(&self, secs: u64) {
+        self.now.fetch_add(secs * SECOND, Ordering::SeqCst);
+    }
+
+    fn sonic_balance(&self, ledger: &FakeLedger) -> Nat {
+        self.sonic.balance(ledger.canister_id, self.bot)
+    }
+}
+
+impl Env for FakeEnv {
+    fn sonic_at(&self, canister_id: Principal) -> Box<dyn Sonic + '_> {
+        if canister_id == self.sonic.canister_id {
+            Box::new(&self.sonic)
+        } else {
+            Box::new(&self.missing_sonic)
+        }
+    }
+
+    fn token(&self, canister_id: Principal) -> Box<dyn Dip20 + '_> {
+        let ledger = self
+            .ledgers
+            .get(&canister_id)
+            .unwrap_or(&self.missing_ledger);
+
+        Box::new(ledger.called_by(self.bot))
+    }
+
+    fn xtc_at(&self, canister_id: Principal) -> Box<dyn XTC + '_> {
+        if canister_id == self.xtc.canister_id {
+            Box::new(&self.xtc_minter)
+        } else {
+            Box::new(&self.missing_xtc)
+        }
+    }
+
+    fn id(&self) -> Principal {
+        self.bot
+    }
+
+    fn time(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    fn print(&self, message: &str) {
+        self.logs.lock().unwrap().push(String::from(message));
+    }
+}
+
+/// A fresh bot trading XTC and WICP through one pool with equal reserves
+fn setup() -> (MutexGuard<'static, ()>, &'static FakeEnv) {
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let bot = Principal::from_slice(&[1]);
+    let xtc = Arc::new(FakeLedger::new(Principal::from_slice(&[2]), "XTC", 12));
+    let wicp = Arc::new(FakeLedger::new(Principal::from_slice(&[3]), "WICP", 12));
+    let ghost = Arc::new(FakeLedger::new(Principal::from_slice(&[6]), "GHOST", 12));
+    let sonic = FakeSonic::new(
+        Principal::from_slice(&[4]),
+        bot,
+        &[xtc.clone(), wicp.clone(), ghost.clone()],
+    );
+    sonic.set_reserves(
+        xtc.canister_id,
+        wicp.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE),
+    );
+
+    let missing_ledger = Arc::new(FakeLedger::new(Principal::anonymous(), "", 0));
+    missing_ledger.stop();
+    let missing_sonic = FakeSonic::new(Principal::anonymous(), bot, &[]);
+    missing_sonic.stop();
+
+    let env: &'static FakeEnv = Box::leak(Box::new(FakeEnv {
+        bot,
+        now: AtomicU64::new(1_000 * SECOND),
+        ledgers: [xtc.clone(), wicp.clone(), ghost.clone()]
+            .iter()
+            .map(|it| (it.canister_id, it.clone()))
+            .collect(),
+        xtc_minter: FakeXtc::new(bot, xtc.clone()),
+        xtc,
+        wicp,
+        ghost,
+        sonic,
+        missing_xtc: FakeXtc::new(bot, missing_ledger.clone()),
+        missing_ledger,
+        missing_sonic,
+        logs: Mutex::new(Vec::new()),
+    }));
+
+    init(Some(BotConfig {
+        xtc_canister: env.xtc.canister_id,
+        wicp_canister: env.wicp.canister_id,
+        sonic_swap_canister: env.sonic.canister_id,
+        controller: Principal::anonymous(),
+    }));
+    set_cron_state(TaskScheduler::default());
+    set_env(env);
+
+    (guard, env)
+}
+
+/// The fakes answer right away, so a future is done after a single poll
+fn block_on<F: Future>(future: F) -> F::Output {
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    match Pin::new(&mut future).poll(&mut context) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("A fake canister never answered"),
+    }
+}
+
+fn market_order(
+    give_currency: &str,
+    take_currency: &str,
+    directive: OrderDirective,
+) -> MarketOrder {
+    MarketOrder {
+        give_currency: String::from(give_currency),
+        take_currency: String::from(take_currency),
+        directive,
+        max_slippage_bps: None,
+        deadline_secs: None,
+        max_price_impact_bps: None,
+    }
+}
+
+#[test]
+fn deposit_and_withdraw_move_tokens_through_sonic() {
+    let (_guard, env) = setup();
+    env.xtc.mint_to(env.bot, Nat::from(1_000u64));
+
+    block_on(deposit(String::from("XTC"), Nat::from(600u64))).unwrap();
+
+    assert_eq!(env.xtc.balance(env.bot), Nat::from(400u64));
+    assert_eq!(env.xtc.balance(env.sonic.canister_id), Nat::from(600u64));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(600u64));
+
+    block_on(withdraw(String::from("XTC"), Nat::from(100u64))).unwrap();
+
+    assert_eq!(env.xtc.balance(env.bot), Nat::from(500u64));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(500u64));
+}
+
+#[test]
+fn deposit_fails_without_tokens() {
+    let (_guard, env) = setup();
+    env.xtc.mint_to(env.bot, Nat::from(100u64));
+
+    let result = block_on(deposit(String::from("XTC"), Nat::from(600u64)));
+
+    assert!(matches!(result, Err(BotError::Sonic(_))));
+    assert_eq!(env.xtc.balance(env.bot), Nat::from(100u64));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(0u64));
+}
+
+#[test]
+fn withdraw_keeps_reserved_balance() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(1_000u64));
+
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.5),
+        market_order: market_order("WICP", "XTC", OrderDirective::GiveExact(Nat::from(800u64))),
+        time_in_force: None,
+    };
+    block_on(add_order(Order::Limit(limit_order))).unwrap();
+
+    let result = block_on(withdraw(String::from("WICP"), Nat::from(300u64)));
+
+    assert!(matches!(result, Err(BotError::InsufficientBalance { .. })));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(1_000u64));
+}
+
+#[test]
+fn market_order_swaps_at_the_pool_price() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::GiveExact(Nat::from(1_000u64)),
+    );
+    assert_eq!(block_on(add_order(Order::Market(order))).unwrap(), None);
+
+    let expected = quote::get_amount_out(
+        &Nat::from(1_000u64),
+        &Nat::from(RESERVE),
+        &Nat::from(RESERVE),
+    )
+    .unwrap();
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert_eq!(env.sonic_balance(&env.wicp), expected);
+
+    let history = get_trade_history(0, 10, None);
+    assert_eq!(history.total, 1);
+    assert_eq!(history.records[0].take_amount, expected);
+    assert_eq!(history.records[0].path, vec!["XTC", "WICP"]);
+}
+
+#[test]
+fn market_order_takes_exact_amount() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::TakeExact(Nat::from(1_000u64)),
+    );
+    block_on(add_order(Order::Market(order))).unwrap();
+
+    let expected = quote::get_amount_in(
+        &Nat::from(1_000u64),
+        &Nat::from(RESERVE),
+        &Nat::from(RESERVE),
+    )
+    .unwrap();
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(1_000u64));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(10_000u64) - expected);
+}
+
+#[test]
+fn market_order_fails_without_balance() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(500u64));
+
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::GiveExact(Nat::from(1_000u64)),
+    );
+    let result = block_on(add_order(Order::Market(order)));
+
+    assert!(matches!(result, Err(BotError::InsufficientBalance { .. })));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(500u64));
+    assert_eq!(get_trade_history(0, 10, None).total, 0);
+}
+
+#[test]
+fn market_order_fails_on_price_impact() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(RESERVE));
+
+    // a tenth of the pool moves the price by far more than the default 5%
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::GiveExact(Nat::from(RESERVE / 10)),
+    );
+    let result = block_on(add_order(Order::Market(order)));
+
+    assert!(matches!(result, Err(BotError::PriceImpactTooHigh { .. })));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(0u64));
+}
+
+#[test]
+fn market_order_fails_when_sonic_rejects_the_call() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+    env.sonic.reject("swapExactTokensForTokens");
+
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::GiveExact(Nat::from(1_000u64)),
+    );
+    let result = block_on(add_order(Order::Market(order)));
+
+    assert!(matches!(result, Err(BotError::CallRejected { .. })));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(10_000u64));
+}
+
+#[test]
+fn limit_order_fills_once_price_reaches_target() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    // one XTC costs one WICP now, the order waits until it's cheaper
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.9),
+        market_order: market_order(
+            "WICP",
+            "XTC",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+        time_in_force: None,
+    };
+    let task_id = block_on(add_order(Order::Limit(limit_order)))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        my_reserved_balance(String::from("WICP")),
+        Nat::from(1_000u64)
+    );
+
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE / 10 * 8),
+    );
+
+    // the order is only checked again once its interval has passed
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(9_000u64));
+    assert!(env.sonic_balance(&env.xtc) > 1_000u64);
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+}
+
+#[test]
+fn cancelled_order_releases_its_funds() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.9),
+        market_order: market_order(
+            "WICP",
+            "XTC",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+        time_in_force: None,
+    };
+    let task_id = block_on(add_order(Order::Limit(limit_order)))
+        .unwrap()
+        .unwrap();
+
+    let info = cancel_order(task_id).unwrap();
+
+    assert_eq!(info.task_id, task_id);
+    assert!(list_orders().is_empty());
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Cancelled)
+    ));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+    assert!(matches!(
+        cancel_order(task_id),
+        Err(BotError::OrderNotFound(id)) if id == task_id
+    ));
+
+    // the price the order waited for comes, but there is nothing left to fill
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE / 10 * 8),
+    );
+    env.advance(10);
+    tick();
+
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(10_000u64));
+}
+
+#[test]
+fn limit_orders_of_the_first_version_survive_an_upgrade() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let config = get_config();
+    let legacy_state = LegacyState {
+        xtc_canister: config.xtc_canister,
+        wicp_canister: config.wicp_canister,
+        sonic_swap_canister: config.sonic_swap_canister,
+        controller: config.controller,
+    };
+    let legacy_order = LegacyLimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.9),
+        market_order: LegacyMarketOrder {
+            give_currency: LegacyCurrency::WICP,
+            take_currency: LegacyCurrency::XTC,
+            directive: OrderDirective::GiveExact(Nat::from(1_000u64)),
+        },
+    };
+    let mut cron_state = TaskScheduler::default();
+    let task_id = cron_state
+        .enqueue(
+            legacy_order,
+            SchedulingInterval {
+                delay_nano: 0,
+                interval_nano: 10 * SECOND,
+                iterations: Iterations::Exact(1),
+            },
+            env.time(),
+        )
+        .unwrap();
+
+    // what the first version leaves in stable memory doesn't fit the current state
+    let saved = encode_args((legacy_state, cron_state)).unwrap();
+    assert!(decode_args::<(State, TaskScheduler)>(&saved).is_err());
+
+    let (legacy_state, mut cron_state): (LegacyState, TaskScheduler) = decode_args(&saved).unwrap();
+    let state = migrate_legacy_state(legacy_state, &mut cron_state);
+    unsafe { STATE = Some(state) }
+    set_cron_state(cron_state);
+
+    assert_eq!(get_config().sonic_swap_canister, env.sonic.canister_id);
+    let orders = list_orders();
+    assert_eq!(orders.len(), 1);
+    assert!(matches!(
+        &orders[0].order,
+        ScheduledOrder::Limit(it) if it.market_order.give_currency == "WICP"
+    ));
+    assert_eq!(
+        my_reserved_balance(String::from("WICP")),
+        Nat::from(1_000u64)
+    );
+
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE / 10 * 8),
+    );
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(9_000u64));
+}
+
+#[test]
+fn xtc_is_minted_for_own_cycles() {
+    let (_guard, env) = setup();
+
+    block_on(mint_xtc_with_own_cycles(5_000)).unwrap();
+
+    assert_eq!(env.xtc.balance(env.bot), Nat::from(5_000u64));
+}
+
+#[test]
+fn config_only_switches_to_canisters_that_answer() {
+    let (_guard, env) = setup();
+    let config = get_config();
+
+    block_on(update_config(config)).unwrap();
+
+    let result = block_on(update_config(BotConfig {
+        sonic_swap_canister: Principal::from_slice(&[5]),
+        ..config
+    }));
+
+    assert!(matches!(result, Err(BotError::CallRejected { .. })));
+    assert_eq!(get_config().sonic_swap_canister, env.sonic.canister_id);
+}
+
+fn protective_order(trigger_price: f64) -> ProtectiveOrder {
+    ProtectiveOrder {
+        trigger_price,
+        market_order: market_order(
+            "XTC",
+            "WICP",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+    }
+}
+
+#[test]
+fn orders_with_invalid_prices_are_rejected() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let result = block_on(add_order(Order::StopLoss(protective_order(f64::NAN))));
+    assert!(matches!(result, Err(BotError::InvalidArgument(_))));
+
+    let result = block_on(add_order(Order::TakeProfit(protective_order(-1.0))));
+    assert!(matches!(result, Err(BotError::InvalidArgument(_))));
+
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::MoreThan(f64::INFINITY),
+        market_order: market_order(
+            "XTC",
+            "WICP",
+            OrderDirective::TakeExact(Nat::from(1_000u64)),
+        ),
+        time_in_force: None,
+    };
+    let result = block_on(add_order(Order::Limit(limit_order)));
+    assert!(matches!(result, Err(BotError::InvalidArgument(_))));
+
+    assert!(list_orders().is_empty());
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+fn rebalance_config(interval_secs: u64) -> RebalanceConfig {
+    RebalanceConfig {
+        targets: vec![
+            TargetWeight {
+                currency: String::from("XTC"),
+                weight_bps: 5_000,
+            },
+            TargetWeight {
+                currency: String::from("WICP"),
+                weight_bps: 5_000,
+            },
+        ],
+        quote_currency: String::from("WICP"),
+        drift_threshold_bps: 500,
+        interval_secs,
+    }
+}
+
+#[test]
+fn rebalancing_restarts_in_place_and_only_stops_once() {
+    let (_guard, _env) = setup();
+
+    start_rebalancing(rebalance_config(60)).unwrap();
+    start_rebalancing(rebalance_config(120)).unwrap();
+
+    let tasks = get_cron_state().get_tasks();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].scheduling_interval.interval_nano, 120 * SECOND);
+
+    stop_rebalancing().unwrap();
+
+    assert!(get_cron_state().get_tasks().is_empty());
+    assert!(matches!(
+        stop_rebalancing(),
+        Err(BotError::InvalidArgument(_))
+    ));
+}
+
+fn grid_config() -> GridConfig {
+    GridConfig {
+        base_currency: String::from("XTC"),
+        quote_currency: String::from("WICP"),
+        lower_price: 0.5,
+        upper_price: 2.0,
+        levels: 4,
+        level_size: Nat::from(100u64),
+    }
+}
+
+#[test]
+fn tokens_used_by_a_grid_stay_registered() {
+    let (_guard, _env) = setup();
+
+    let grid_id = block_on(start_grid(grid_config())).unwrap();
+
+    let result = unregister_token(String::from("WICP"));
+    assert!(matches!(result, Err(BotError::InvalidArgument(message)) if message.contains("grid")));
+
+    stop_grid(grid_id).unwrap();
+
+    unregister_token(String::from("WICP")).unwrap();
+}
+
+/// Makes XTC worth half as much WICP as before
+fn halve_xtc_price(env: &FakeEnv) {
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from(RESERVE * 2),
+        Nat::from(RESERVE),
+    );
+}
+
+#[test]
+fn stop_loss_is_tried_again_after_a_rejected_swap() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let task_id = block_on(add_order(Order::StopLoss(protective_order(1.5))))
+        .unwrap()
+        .unwrap();
+
+    halve_xtc_price(env);
+    env.sonic.reject("swapExactTokensForTokens");
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+    assert_eq!(get_order(task_id).unwrap().failed_attempts, 1);
+    assert_eq!(
+        my_reserved_balance(String::from("XTC")),
+        Nat::from(1_000u64)
+    );
+
+    env.sonic.accept("swapExactTokensForTokens");
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+#[test]
+fn stop_loss_fails_once_it_runs_out_of_attempts() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let task_id = block_on(add_order(Order::StopLoss(protective_order(1.5))))
+        .unwrap()
+        .unwrap();
+
+    halve_xtc_price(env);
+    env.sonic.reject("swapExactTokensForTokens");
+    for _ in 1..MAX_EXECUTION_ATTEMPTS {
+        tick();
+        env.advance(10);
+    }
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Failed)
+    ));
+    assert!(list_orders().is_empty());
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(10_000u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+#[test]
+fn trailing_stop_fires_once_value_falls_from_its_peak() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let trailing_stop = TrailingStopOrder {
+        distance: TrailingDistance::Bps(1_000),
+        market_order: market_order(
+            "XTC",
+            "WICP",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+    };
+    let task_id = block_on(add_order(Order::TrailingStop(trailing_stop)))
+        .unwrap()
+        .unwrap();
+
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    halve_xtc_price(env);
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+}
+
+#[test]
+fn oco_cancels_other_legs_once_one_fills() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let legs = vec![
+        ScheduledOrder::StopLoss(protective_order(1.5)),
+        ScheduledOrder::TakeProfit(protective_order(0.5)),
+    ];
+    let group_id = block_on(add_order(Order::Oco(legs))).unwrap().unwrap();
+    let orders = list_orders();
+    let (stop_loss, take_profit) = (orders[0].task_id, orders[1].task_id);
+    assert_eq!(
+        get_oco_group(group_id).unwrap().group.legs,
+        vec![stop_loss, take_profit]
+    );
+    assert_eq!(orders[1].oco_group_id, Some(group_id));
+
+    halve_xtc_price(env);
+    tick();
+
+    assert!(matches!(
+        get_order_status(stop_loss),
+        Some(OrderStatus::Filled)
+    ));
+    assert!(matches!(
+        get_order_status(take_profit),
+        Some(OrderStatus::Cancelled)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+#[test]
+fn oco_keeps_other_legs_when_one_fails() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let legs = vec![
+        ScheduledOrder::StopLoss(protective_order(1.5)),
+        ScheduledOrder::TakeProfit(protective_order(0.5)),
+    ];
+    block_on(add_order(Order::Oco(legs))).unwrap();
+    let orders = list_orders();
+    let (stop_loss, take_profit) = (orders[0].task_id, orders[1].task_id);
+
+    halve_xtc_price(env);
+    env.sonic.reject("swapExactTokensForTokens");
+    for _ in 0..MAX_EXECUTION_ATTEMPTS {
+        tick();
+        env.advance(10);
+    }
+
+    assert!(matches!(
+        get_order_status(stop_loss),
+        Some(OrderStatus::Failed)
+    ));
+    assert!(matches!(
+        get_order_status(take_profit),
+        Some(OrderStatus::Pending)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(10_000u64));
+    assert_eq!(
+        my_reserved_balance(String::from("XTC")),
+        Nat::from(1_000u64)
+    );
+    assert!(env.logs.lock().unwrap()[0].contains("CallRejected"));
+}
+
+#[test]
+fn cancelling_an_oco_group_cancels_every_leg() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let legs = vec![
+        ScheduledOrder::StopLoss(protective_order(0.5)),
+        ScheduledOrder::TakeProfit(protective_order(1.5)),
+    ];
+    let first_group = block_on(add_order(Order::Oco(legs.clone())))
+        .unwrap()
+        .unwrap();
+    let second_group = block_on(add_order(Order::Oco(legs))).unwrap().unwrap();
+    assert_ne!(first_group, second_group);
+
+    let info = cancel_oco_group(second_group).unwrap();
+
+    assert_eq!(info.pending_legs.len(), 2);
+    for leg in &info.pending_legs {
+        assert!(matches!(
+            get_order_status(leg.task_id),
+            Some(OrderStatus::Cancelled)
+        ));
+    }
+    assert!(matches!(
+        get_oco_group(second_group).unwrap().group.status,
+        OcoStatus::Cancelled
+    ));
+    assert!(matches!(
+        cancel_oco_group(second_group),
+        Err(BotError::OcoGroupNotFound(_))
+    ));
+
+    // the first group is untouched and goes away with any of its legs
+    assert_eq!(list_orders().len(), 2);
+    assert_eq!(
+        my_reserved_balance(String::from("XTC")),
+        Nat::from(1_000u64)
+    );
+
+    let leg = get_oco_group(first_group).unwrap().pending_legs[1].task_id;
+    cancel_order(leg).unwrap();
+
+    assert!(list_orders().is_empty());
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+/// Buys XTC with WICP once XTC gets cheaper than it is now
+fn waiting_limit_order(time_in_force: TimeInForce) -> Order {
+    Order::Limit(LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.9),
+        market_order: market_order(
+            "WICP",
+            "XTC",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+        time_in_force: Some(time_in_force),
+    })
+}
+
+#[test]
+fn limit_order_expires_at_its_deadline() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let expires_at = env.time() + 15 * SECOND;
+    let task_id = block_on(add_order(waiting_limit_order(TimeInForce::ExpiresAt(
+        expires_at,
+    ))))
+    .unwrap()
+    .unwrap();
+
+    tick();
+    env.advance(10);
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Expired)
+    ));
+    assert!(list_orders().is_empty());
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+}
+
+#[test]
+fn limit_order_expires_after_its_checks_run_out() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let task_id = block_on(add_order(waiting_limit_order(TimeInForce::MaxChecks(2))))
+        .unwrap()
+        .unwrap();
+
+    for _ in 0..2 {
+        tick();
+        env.advance(10);
+    }
+    assert_eq!(get_order(task_id).unwrap().checks_made, 2);
+
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Expired)
+    ));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(10_000u64));
+}
+
+#[test]
+fn twap_order_gives_its_amount_in_slices() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+
+    let twap = TwapOrder {
+        market_order: market_order(
+            "XTC",
+            "WICP",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+        slices: 3,
+        interval_secs: 60,
+        price_limit: None,
+    };
+    let task_id = block_on(add_order(Order::Twap(twap))).unwrap().unwrap();
+
+    tick();
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_667u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(667u64));
+
+    // the next slice waits for its interval
+    env.advance(30);
+    tick();
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_667u64));
+
+    env.advance(30);
+    tick();
+    env.advance(60);
+    tick();
+
+    let progress = get_order_progress(task_id).unwrap();
+    let slices: Vec<_> = progress
+        .runs
+        .iter()
+        .map(|it| match &it.outcome {
+            RunOutcome::Filled { give_amount, .. } => give_amount.clone(),
+            _ => panic!("Every slice should be filled"),
+        })
+        .collect();
+    assert_eq!(
+        slices,
+        vec![Nat::from(333u64), Nat::from(333u64), Nat::from(334u64)]
+    );
+    assert_eq!(progress.filled_give_amount, Nat::from(1_000u64));
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+#[test]
+fn recurring_order_skips_runs_above_its_price_ceiling() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    // one XTC costs one WICP now, so the first run is skipped
+    let recurring = RecurringOrder {
+        market_order: market_order("WICP", "XTC", OrderDirective::GiveExact(Nat::from(100u64))),
+        interval_secs: 60,
+        runs: Some(2),
+        ends_at: None,
+        max_price: Some(0.9),
+    };
+    let task_id = block_on(add_order(Order::Recurring(recurring)))
+        .unwrap()
+        .unwrap();
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(100u64));
+
+    tick();
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(10_000u64));
+
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE / 10 * 8),
+    );
+    env.advance(60);
+    tick();
+
+    let progress = get_order_progress(task_id).unwrap();
+    assert!(matches!(
+        progress.runs[0].outcome,
+        RunOutcome::Skipped { .. }
+    ));
+    assert!(matches!(
+        progress.runs[1].outcome,
+        RunOutcome::Filled { .. }
+    ));
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(9_900u64));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+}
+
+#[test]
+fn recurring_order_stops_at_its_end_time() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let recurring = RecurringOrder {
+        market_order: market_order("WICP", "XTC", OrderDirective::GiveExact(Nat::from(100u64))),
+        interval_secs: 60,
+        runs: None,
+        ends_at: Some(env.time() + 90 * SECOND),
+        max_price: None,
+    };
+    let task_id = block_on(add_order(Order::Recurring(recurring)))
+        .unwrap()
+        .unwrap();
+
+    for _ in 0..3 {
+        tick();
+        env.advance(60);
+    }
+
+    assert_eq!(get_order_progress(task_id).unwrap().runs.len(), 2);
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert!(list_orders().is_empty());
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(9_800u64));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+}
+
+/// Makes one XTC worth `price` WICP
+fn set_xtc_price(env: &FakeEnv, price: f64) {
+    env.sonic.set_reserves(
+        env.xtc.canister_id,
+        env.wicp.canister_id,
+        Nat::from((RESERVE as f64 / price) as u64),
+        Nat::from(RESERVE),
+    );
+}
+
+#[test]
+fn grid_with_infinite_bounds_is_rejected() {
+    let (_guard, _env) = setup();
+
+    let config = GridConfig {
+        upper_price: f64::INFINITY,
+        ..grid_config()
+    };
+
+    assert!(matches!(
+        block_on(start_grid(config)),
+        Err(BotError::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn grid_buys_each_crossed_level_once_and_locks_what_it_holds() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+    set_xtc_price(env, 1.2);
+
+    let grid_id = block_on(start_grid(grid_config())).unwrap();
+    tick();
+
+    // the levels at 1.0 and 0.5 are crossed at once
+    set_xtc_price(env, 0.4);
+    env.advance(10);
+    tick();
+    env.advance(10);
+    tick();
+
+    let grid = get_grid(grid_id).unwrap();
+    assert!(grid.levels[0].position.is_some());
+    assert!(grid.levels[1].position.is_some());
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(200u64));
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(200u64));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(0u64));
+
+    let result = block_on(withdraw(String::from("XTC"), Nat::from(100u64)));
+    assert!(matches!(result, Err(BotError::InsufficientBalance { .. })));
+
+    stop_grid(grid_id).unwrap();
+
+    assert_eq!(my_reserved_balance(String::from("XTC")), Nat::from(0u64));
+}
+
+#[test]
+fn grid_leaves_funds_of_pending_orders_alone() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(1_000u64));
+
+    // WICP for more than the grid needs is already promised to an order
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.1),
+        market_order: market_order("WICP", "XTC", OrderDirective::GiveExact(Nat::from(990u64))),
+        time_in_force: None,
+    };
+    block_on(add_order(Order::Limit(limit_order))).unwrap();
+
+    let grid_id = block_on(start_grid(grid_config())).unwrap();
+    tick();
+
+    set_xtc_price(env, 0.4);
+    env.advance(10);
+    tick();
+
+    assert!(get_grid(grid_id).unwrap().levels[0].position.is_none());
+    assert!(get_state().grids.buying.is_empty());
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(1_000u64));
+    assert_eq!(my_reserved_balance(String::from("WICP")), Nat::from(990u64));
+    assert!(env
+        .logs
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|it| it.contains("InsufficientBalance")));
+}
+
+#[test]
+fn limit_order_without_a_direct_pool_fills_through_a_route() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+    block_on(register_token(env.ghost.canister_id)).unwrap();
+    env.sonic.set_reserves(
+        env.wicp.canister_id,
+        env.ghost.canister_id,
+        Nat::from(RESERVE),
+        Nat::from(RESERVE),
+    );
+
+    // XTC only gets to GHOST through WICP
+    let limit_order = LimitOrder {
+        target_price_condition: TargetPrice::LessThan(0.9),
+        market_order: market_order(
+            "XTC",
+            "GHOST",
+            OrderDirective::GiveExact(Nat::from(1_000u64)),
+        ),
+        time_in_force: None,
+    };
+    let task_id = block_on(add_order(Order::Limit(limit_order)))
+        .unwrap()
+        .unwrap();
+
+    tick();
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Pending)
+    ));
+
+    env.sonic.set_reserves(
+        env.wicp.canister_id,
+        env.ghost.canister_id,
+        Nat::from(RESERVE / 10 * 8),
+        Nat::from(RESERVE),
+    );
+    env.advance(10);
+    tick();
+
+    assert!(matches!(
+        get_order_status(task_id),
+        Some(OrderStatus::Filled)
+    ));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert!(env.sonic_balance(&env.ghost) > 1_000u64);
+
+    let history = get_trade_history(0, 1, None);
+    assert_eq!(history.records[0].path, vec!["XTC", "WICP", "GHOST"]);
+}
+
+#[test]
+fn liquidity_is_added_and_removed_at_the_pool_price() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(10_000u64));
+    env.sonic
+        .credit(env.wicp.canister_id, env.bot, Nat::from(10_000u64));
+
+    let receipt = block_on(add_liquidity(
+        String::from("XTC"),
+        String::from("WICP"),
+        Nat::from(1_000u64),
+        None,
+    ))
+    .unwrap();
+
+    assert_eq!(receipt.amount_b, Nat::from(1_000u64));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(9_000u64));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(9_000u64));
+
+    let positions = block_on(list_lp_positions()).unwrap();
+    assert_eq!(positions.len(), 1);
+    assert_eq!(positions[0].lp_balance, Nat::from(1_000u64));
+
+    block_on(remove_liquidity(
+        String::from("XTC"),
+        String::from("WICP"),
+        positions[0].lp_balance.clone(),
+        None,
+    ))
+    .unwrap();
+
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(10_000u64));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(10_000u64));
+    assert!(block_on(list_lp_positions()).unwrap().is_empty());
+}
+
+#[test]
+fn paper_orders_settle_in_virtual_balances() {
+    let (_guard, env) = setup();
+    set_mode(Mode::Paper).unwrap();
+    set_paper_balance(String::from("XTC"), Nat::from(10_000u64)).unwrap();
+
+    let order = market_order(
+        "XTC",
+        "WICP",
+        OrderDirective::GiveExact(Nat::from(1_000u64)),
+    );
+    block_on(add_order(Order::Market(order))).unwrap();
+
+    let expected = quote::get_amount_out(
+        &Nat::from(1_000u64),
+        &Nat::from(RESERVE),
+        &Nat::from(RESERVE),
+    )
+    .unwrap();
+    assert_eq!(
+        block_on(my_sonic_balance(String::from("XTC"))).unwrap(),
+        Nat::from(9_000u64)
+    );
+    assert_eq!(
+        block_on(my_sonic_balance(String::from("WICP"))).unwrap(),
+        expected
+    );
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(0u64));
+    assert_eq!(env.sonic_balance(&env.wicp), Nat::from(0u64));
+}
+
+#[test]
+fn withdraw_is_rejected_in_paper_mode() {
+    let (_guard, env) = setup();
+    env.sonic
+        .credit(env.xtc.canister_id, env.bot, Nat::from(1_000u64));
+    set_mode(Mode::Paper).unwrap();
+    set_paper_balance(String::from("XTC"), Nat::from(1_000u64)).unwrap();
+
+    let result = block_on(withdraw(String::from("XTC"), Nat::from(500u64)));
+
+    assert!(matches!(result, Err(BotError::InvalidArgument(_))));
+    assert_eq!(env.sonic_balance(&env.xtc), Nat::from(1_000u64));
+    assert_eq!(env.xtc.balance(env.bot), Nat::from(0u64));
+}
+
+#[test]
+fn mode_is_locked_while_a_grid_runs() {
+    let (_guard, _env) = setup();
+
+    let grid_id = block_on(start_grid(grid_config())).unwrap();
+
+    let result = set_mode(Mode::Paper);
+    assert!(matches!(result, Err(BotError::InvalidArgument(message)) if message.contains("grid")));
+    assert_eq!(get_mode(), Mode::Live);
+
+    stop_grid(grid_id).unwrap();
+
+    set_mode(Mode::Paper).unwrap();
+    assert_eq!(get_mode(), Mode::Paper);
+}
+
+#[test]
+fn stopped_rebalancer_releases_mode_and_tokens() {
+    let (_guard, _env) = setup();
+
+    start_rebalancing(rebalance_config(60)).unwrap();
+
+    let result = set_mode(Mode::Paper);
+    assert!(
+        matches!(result, Err(BotError::InvalidArgument(message)) if message.contains("rebalancer"))
+    );
+    let result = unregister_token(String::from("XTC"));
+    assert!(
+        matches!(result, Err(BotError::InvalidArgument(message)) if message.contains("rebalancer"))
+    );
+
+    stop_rebalancing().unwrap();
+
+    set_mode(Mode::Paper).unwrap();
+    assert_eq!(get_mode(), Mode::Paper);
+    unregister_token(String::from("XTC")).unwrap();
+}